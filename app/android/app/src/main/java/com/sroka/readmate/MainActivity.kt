@@ -5,6 +5,7 @@ import androidx.appcompat.app.AppCompatActivity
 import com.sroka.readmate.books.BooksFragment
 import com.sroka.readmate.R
 import org.koin.android.ext.android.inject
+import uniffi.global_bindings.GlobalSideEffect
 import uniffi.global_bindings.GlobalState
 import uniffi.global_bindings.GlobalStateListener
 import uniffi.global_bindings.GlobalStore
@@ -32,4 +33,6 @@ class MainActivity : AppCompatActivity(), GlobalStateListener, IdentityId {
         println("New global state: $state")
         state.destroy()
     }
+
+    override fun newSideEffect(sideEffect: GlobalSideEffect) = Unit
 }
@@ -11,6 +11,7 @@ import org.koin.dsl.onClose
 import uniffi.global_bindings.BooksStore
 import uniffi.global_bindings.GlobalStore
 import uniffi.global_bindings.PagesStore
+import uniffi.global_bindings.PixelFormat
 
 class MainApplication : Application() {
     override fun onCreate() {
@@ -28,7 +29,7 @@ class MainApplication : Application() {
 }
 
 val globalModule = module {
-    single { GlobalStore().apply { init() } }.onClose { it?.destroy() }
+    single { GlobalStore(storageDir = androidContext().filesDir.absolutePath).apply { init(pageCacheBytes = (Runtime.getRuntime().maxMemory() / 4).toULong(), pixelFormat = PixelFormat.RGBA, librarySearchPaths = listOf(androidContext().applicationInfo.nativeLibraryDir)) } }.onClose { it?.destroy() }
     scope<BooksFragment> {
         scoped { BooksStore(globalStore = get()).apply { init() } }.onClose { it?.destroy() }
     }
@@ -1,9 +1,15 @@
 package com.sroka.readmate
 
 import android.graphics.Bitmap
+import android.graphics.BitmapFactory
 import android.os.Looper
 import android.util.Log
 import android.view.View
+import android.widget.TextView
+import androidx.core.view.isVisible
+import com.sun.jna.Pointer
+import uniffi.global_bindings.EngineStatus
+import uniffi.global_bindings.PixelFormat
 import java.lang.ref.SoftReference
 
 
@@ -15,13 +21,43 @@ fun uniffi.global_bindings.Bitmap.getFromCacheOrCreate(): Bitmap {
         existingBitmap
     } else {
         Log.w("UNIFFI_BITMAP_UTIL", "Bitmap ${uid} not cached - creating a new one")
-        val pixels = copyPixels().toUIntArray().toIntArray()
-        val newBitmap = Bitmap.createBitmap(pixels, width(), height(), Bitmap.Config.ARGB_8888);
+        val encoding = encoding()
+        val newBitmap = if (encoding != null) {
+            val bytes = encodedBytes(encoding).toUByteArray().toByteArray()
+            BitmapFactory.decodeByteArray(bytes, 0, bytes.size)
+        } else when (pixelFormat()) {
+            // Rgba is the memory layout of ARGB_8888, so the pixels are copied straight from the native buffer
+            PixelFormat.RGBA -> {
+                val address = pixelsAddress().toLong()
+                // 0 when there are no pixels in memory to read
+                if (address != 0L) {
+                    Bitmap.createBitmap(width(), height(), Bitmap.Config.ARGB_8888).apply {
+                        copyPixelsFromBuffer(Pointer(address).getByteBuffer(0, byteCount().toLong()))
+                    }
+                } else {
+                    createFromCopiedPixels()
+                }
+            }
+            PixelFormat.ARGB -> createFromCopiedPixels()
+        }
         bitmapMap[uid] = SoftReference(newBitmap)
         newBitmap
     }
 }
 
+private fun uniffi.global_bindings.Bitmap.createFromCopiedPixels(): Bitmap {
+    val pixels = copyPixels().toUIntArray().toIntArray()
+    return Bitmap.createBitmap(pixels, width(), height(), Bitmap.Config.ARGB_8888)
+}
+
+// Books can't be opened without pdfium, which is spelled out over the screen
+fun TextView.showEngineStatus(engineStatus: EngineStatus) {
+    isVisible = engineStatus is EngineStatus.Unavailable
+    if (engineStatus is EngineStatus.Unavailable) {
+        setText(R.string.engine_unavailable_message)
+    }
+}
+
 fun View.assureMainThread(block: () -> Unit) {
     if (Thread.currentThread() == Looper.getMainLooper().thread) block() else post { block() }
 }
@@ -1,11 +1,16 @@
 package com.sroka.readmate.books
 
+import android.content.Intent
 import android.os.Bundle
 import android.provider.OpenableColumns
+import android.text.InputType
 import android.view.LayoutInflater
 import android.view.View
 import android.view.ViewGroup
+import android.widget.EditText
 import android.widget.TextView
+import android.widget.Toast
+import androidx.appcompat.app.AlertDialog
 import androidx.activity.result.contract.ActivityResultContracts
 import androidx.recyclerview.widget.GridLayoutManager
 import androidx.recyclerview.widget.RecyclerView
@@ -14,6 +19,7 @@ import com.sroka.readmate.IdentityId
 import com.sroka.readmate.R
 import com.sroka.readmate.assureMainThread
 import com.sroka.readmate.pages.PagesFragment
+import com.sroka.readmate.showEngineStatus
 import kotlin.concurrent.thread
 import org.koin.android.ext.android.inject
 import org.koin.androidx.scope.ScopeFragment
@@ -29,6 +35,7 @@ class BooksFragment : ScopeFragment(), BooksStateListener, BookClickedListener,
     private val booksStore: BooksStore by inject()
 
     private var emptyLibraryText: TextView? = null
+    private var engineStatusText: TextView? = null
     private var content: RecyclerView? = null
     private var contentAdapter: BooksRecyclerViewAdapter? = null
     private var addButton: FloatingActionButton? = null
@@ -53,12 +60,13 @@ class BooksFragment : ScopeFragment(), BooksStateListener, BookClickedListener,
 
                     } ?: existingUri.lastPathSegment ?: "Unknown file name"
 
-                val bytes = activity
+                // The store takes over the descriptor and reads the file itself
+                val fd = activity
                     ?.contentResolver
-                    ?.openInputStream(existingUri)
-                    ?.use { it.readBytes() }
-                if (bytes != null) {
-                    booksStore.dispatchAction(BooksAction.LoadPdf(pdfUuid, fileName, bytes.toUByteArray().asList()))
+                    ?.openFileDescriptor(existingUri, "r")
+                    ?.detachFd()
+                if (fd != null) {
+                    booksStore.dispatchAction(BooksAction.LoadPdfFromFd(pdfUuid, fileName, fd))
                 } else {
                     booksStore.dispatchAction(BooksAction.MarkPdfLoadingFailed(uuid = pdfUuid))
                 }
@@ -66,12 +74,28 @@ class BooksFragment : ScopeFragment(), BooksStateListener, BookClickedListener,
         }
     }
 
+    private var pendingSaveFileBytes: ByteArray? = null
+
+    private val createDocument = registerForActivityResult(ActivityResultContracts.CreateDocument("*/*")) { uri ->
+        val bytes = pendingSaveFileBytes
+        pendingSaveFileBytes = null
+        if (uri != null && bytes != null) {
+            thread(true) {
+                activity
+                    ?.contentResolver
+                    ?.openOutputStream(uri)
+                    ?.use { it.write(bytes) }
+            }
+        }
+    }
+
     override fun onCreateView(
         inflater: LayoutInflater, container: ViewGroup?,
         savedInstanceState: Bundle?,
     ): View? {
         val view = inflater.inflate(R.layout.fragment_books_list, container, false)
         emptyLibraryText = view.findViewById(R.id.empty_library_message)
+        engineStatusText = view.findViewById(R.id.engine_status_message)
         content = view.findViewById(R.id.books_list)
         addButton = view.findViewById(R.id.add_book_button)
         val gridLayoutManager = GridLayoutManager(context, 2)
@@ -94,6 +118,7 @@ class BooksFragment : ScopeFragment(), BooksStateListener, BookClickedListener,
         addButton = null
         content = null
         addButton = null
+        engineStatusText = null
         super.onDestroyView()
     }
 
@@ -103,6 +128,7 @@ class BooksFragment : ScopeFragment(), BooksStateListener, BookClickedListener,
 
     private fun render(state: BooksState) {
         println("New books state: ${Thread.currentThread().name} $state")
+        engineStatusText?.showEngineStatus(state.engineStatus)
         contentAdapter?.submitList(state.books) {
             state.destroy()
         }
@@ -111,14 +137,66 @@ class BooksFragment : ScopeFragment(), BooksStateListener, BookClickedListener,
     override fun newSideEffect(sideEffect: BooksSideEffect) {
         when (sideEffect) {
             BooksSideEffect.OpenFilePicker -> openFilePicker()
+            is BooksSideEffect.SaveFile -> view?.assureMainThread {
+                saveFile(sideEffect.fileName, sideEffect.bytes.toUByteArray().toByteArray())
+            }
+            is BooksSideEffect.NotesExported -> view?.assureMainThread { shareText(sideEffect.document) }
+            is BooksSideEffect.ExportFailed -> view?.assureMainThread {
+                Toast.makeText(requireContext(), R.string.book_export_failed, Toast.LENGTH_SHORT).show()
+            }
         }
     }
 
     private fun openFilePicker() = getContent.launch("application/pdf")
+
+    private fun saveFile(fileName: String, bytes: ByteArray) {
+        pendingSaveFileBytes = bytes
+        createDocument.launch(fileName)
+    }
+
+    private fun shareText(text: String) {
+        val intent = Intent(Intent.ACTION_SEND)
+            .setType("text/plain")
+            .putExtra(Intent.EXTRA_TEXT, text)
+        startActivity(Intent.createChooser(intent, null))
+    }
+
     override fun onBookClicked(bookId: String) {
+        booksStore.dispatchAction(BooksAction.BookClicked(uuid = bookId))
         parentFragmentManager
             .beginTransaction()
             .replace(R.id.fragment_container, PagesFragment.newInstance(bookId))
             .commit()
     }
+
+    override fun onBookLongClicked(bookId: String) {
+        AlertDialog.Builder(requireContext())
+            .setTitle(R.string.book_delete_title)
+            .setMessage(R.string.book_delete_message)
+            .setPositiveButton(R.string.book_delete_confirm) { _, _ ->
+                booksStore.dispatchAction(BooksAction.DeleteBook(uuid = bookId))
+            }
+            .setNegativeButton(android.R.string.cancel, null)
+            .show()
+    }
+
+    override fun onBookRetryClicked(bookId: String) {
+        booksStore.dispatchAction(BooksAction.RetryLoading(uuid = bookId))
+    }
+
+    override fun onBookPasswordRequired(bookId: String, fileName: String, wrongPassword: Boolean) {
+        val passwordInput = EditText(requireContext()).apply {
+            inputType = InputType.TYPE_CLASS_TEXT or InputType.TYPE_TEXT_VARIATION_PASSWORD
+            setHint(R.string.book_password_hint)
+        }
+        AlertDialog.Builder(requireContext())
+            .setTitle(if (wrongPassword) R.string.book_password_wrong_title else R.string.book_password_required_title)
+            .setMessage(fileName)
+            .setView(passwordInput)
+            .setPositiveButton(R.string.book_password_submit) { _, _ ->
+                booksStore.dispatchAction(BooksAction.SubmitPassword(uuid = bookId, password = passwordInput.text.toString()))
+            }
+            .setNegativeButton(android.R.string.cancel, null)
+            .show()
+    }
 }
@@ -13,11 +13,15 @@ import androidx.recyclerview.widget.RecyclerView
 import com.sroka.readmate.R
 import com.sroka.readmate.getFromCacheOrCreate
 import uniffi.global_bindings.Book
+import uniffi.global_bindings.PdfLoadingError
 import uniffi.global_bindings.PdfLoadingState
 
 
 interface BookClickedListener {
     fun onBookClicked(bookId: String)
+    fun onBookLongClicked(bookId: String)
+    fun onBookPasswordRequired(bookId: String, fileName: String, wrongPassword: Boolean)
+    fun onBookRetryClicked(bookId: String)
 }
 
 class BooksRecyclerViewAdapter : ListAdapter<Book, BooksRecyclerViewAdapter.ViewHolder>(DIFF_CALLBACK) {
@@ -39,12 +43,14 @@ class BooksRecyclerViewAdapter : ListAdapter<Book, BooksRecyclerViewAdapter.View
     override fun onBindViewHolder(holder: ViewHolder, position: Int) {
         val item = getItem(position)
         holder.bookId = item.uuid
+        holder.passwordRequired = item.loadingState as? PdfLoadingState.PasswordRequired
+        holder.retryable = (item.loadingState as? PdfLoadingState.ErrorPdf)?.error?.isRetryable() == true
         when (val loadingState = item.loadingState) {
             is PdfLoadingState.ErrorPdf -> {
                 holder.bookLoadingError.isVisible = true
                 holder.bookLoadingProgressBar.isVisible = false
                 holder.bookTitle.isVisible = true
-                holder.bookTitle.setText(R.string.book_loading_error_title)
+                holder.bookTitle.setText(loadingState.error.messageResource())
             }
 
             is PdfLoadingState.LoadingPdf -> {
@@ -53,11 +59,19 @@ class BooksRecyclerViewAdapter : ListAdapter<Book, BooksRecyclerViewAdapter.View
                 holder.bookTitle.isVisible = false
             }
 
+            is PdfLoadingState.PasswordRequired -> {
+                holder.bookLoadingError.isVisible = false
+                holder.bookLoadingProgressBar.isVisible = false
+                holder.bookTitle.isVisible = true
+                holder.bookTitle.text = loadingState.fileName
+                holder.bookCover.setImageResource(android.R.drawable.ic_lock_lock)
+            }
+
             is PdfLoadingState.ValidPdf -> {
                 holder.bookLoadingError.isVisible = false
                 holder.bookLoadingProgressBar.isVisible = false
                 holder.bookTitle.isVisible = true
-                holder.bookTitle.text = loadingState.title
+                holder.bookTitle.text = item.metadataOverlay.title ?: loadingState.title
                 val thumbnail = item.thumbnail
                 if (thumbnail != null) {
                     holder.bookCover.setImageBitmap(thumbnail.getFromCacheOrCreate())
@@ -71,6 +85,8 @@ class BooksRecyclerViewAdapter : ListAdapter<Book, BooksRecyclerViewAdapter.View
 
     inner class ViewHolder(view: View) : RecyclerView.ViewHolder(view) {
         var bookId: String? = null
+        var passwordRequired: PdfLoadingState.PasswordRequired? = null
+        var retryable = false
 
         val bookCover: ImageView
         val bookTitle: TextView
@@ -82,7 +98,35 @@ class BooksRecyclerViewAdapter : ListAdapter<Book, BooksRecyclerViewAdapter.View
             bookTitle = view.findViewById(R.id.book_title)
             bookLoadingProgressBar = view.findViewById(R.id.book_loading_progress_bar)
             bookLoadingError = view.findViewById(R.id.book_loading_error)
-            view.setOnClickListener { bookId?.let { listener?.onBookClicked(it) } }
+            view.setOnClickListener {
+                val bookId = bookId ?: return@setOnClickListener
+                val passwordRequired = passwordRequired
+                if (passwordRequired != null) {
+                    listener?.onBookPasswordRequired(bookId, passwordRequired.fileName, passwordRequired.wrongPassword)
+                } else if (retryable) {
+                    listener?.onBookRetryClicked(bookId)
+                } else {
+                    listener?.onBookClicked(bookId)
+                }
+            }
+            view.setOnLongClickListener { bookId?.let { listener?.onBookLongClicked(it) } != null }
         }
     }
 }
+
+private fun PdfLoadingError.messageResource(): Int = when (this) {
+    PdfLoadingError.CORRUPT_FILE -> R.string.book_loading_error_corrupt_file
+    PdfLoadingError.UNSUPPORTED_FORMAT -> R.string.book_loading_error_unsupported_format
+    PdfLoadingError.PASSWORD_PROTECTED -> R.string.book_loading_error_password_protected
+    PdfLoadingError.IO -> R.string.book_loading_error_io
+    PdfLoadingError.SOURCE_UNAVAILABLE -> R.string.book_loading_error_source_unavailable
+    PdfLoadingError.ENGINE_UNAVAILABLE -> R.string.book_loading_error_engine_unavailable
+    PdfLoadingError.OUT_OF_MEMORY -> R.string.book_loading_error_out_of_memory
+}
+
+// Mirrors PdfLoadingError::is_retryable of the core
+private fun PdfLoadingError.isRetryable(): Boolean = when (this) {
+    PdfLoadingError.IO, PdfLoadingError.OUT_OF_MEMORY -> true
+    PdfLoadingError.CORRUPT_FILE, PdfLoadingError.UNSUPPORTED_FORMAT, PdfLoadingError.PASSWORD_PROTECTED,
+    PdfLoadingError.SOURCE_UNAVAILABLE, PdfLoadingError.ENGINE_UNAVAILABLE -> false
+}
@@ -5,15 +5,18 @@ import android.view.LayoutInflater
 import android.view.View
 import android.view.ViewGroup
 import android.widget.TextView
+import android.widget.Toast
 import androidx.recyclerview.widget.LinearLayoutManager
 import androidx.recyclerview.widget.RecyclerView
 import androidx.recyclerview.widget.RecyclerView.OnScrollListener
 import com.sroka.readmate.IdentityId
 import com.sroka.readmate.R
 import com.sroka.readmate.assureMainThread
+import com.sroka.readmate.showEngineStatus
 import org.koin.android.ext.android.inject
 import org.koin.androidx.scope.ScopeFragment
 import uniffi.global_bindings.PagesAction
+import uniffi.global_bindings.PagesSideEffect
 import uniffi.global_bindings.PagesState
 import uniffi.global_bindings.PagesStateListener
 import uniffi.global_bindings.PagesStore
@@ -39,7 +42,9 @@ class PagesFragment : ScopeFragment(), PagesStateListener, IdentityId {
     private val pagesStore: PagesStore by inject()
 
     private var content: RecyclerView? = null
+    private var engineStatusText: TextView? = null
     private var contentAdapter: PagesRecyclerViewAdapter? = null
+    private var restoredReadingPosition = false
 
     override fun onCreateView(
         inflater: LayoutInflater, container: ViewGroup?,
@@ -47,6 +52,7 @@ class PagesFragment : ScopeFragment(), PagesStateListener, IdentityId {
     ): View? {
         val view = inflater.inflate(R.layout.fragment_pages_list, container, false)
         content = view.findViewById(R.id.pages_list)
+        engineStatusText = view.findViewById(R.id.engine_status_message)
         content?.layoutManager = LinearLayoutManager(context)
         contentAdapter = PagesRecyclerViewAdapter()
         content?.adapter = contentAdapter
@@ -56,19 +62,38 @@ class PagesFragment : ScopeFragment(), PagesStateListener, IdentityId {
     override fun onViewCreated(view: View, savedInstanceState: Bundle?) {
         super.onViewCreated(view, savedInstanceState)
         pagesStore.addListener(getIdentityId(), this)
+        content?.addOnLayoutChangeListener { layoutView, _, _, _, _, _, _, _, _ ->
+            val density = layoutView.resources.displayMetrics.density
+            pagesStore.dispatchAction(
+                PagesAction.SetViewport(
+                    width = layoutView.width / density,
+                    height = layoutView.height / density,
+                    zoom = 1f,
+                    deviceScale = density,
+                )
+            )
+        }
         content?.addOnScrollListener(object : OnScrollListener() {
             override fun onScrollStateChanged(recyclerView: RecyclerView, newState: Int) {
                 super.onScrollStateChanged(recyclerView, newState)
-                (content?.layoutManager as? LinearLayoutManager)?.findLastVisibleItemPosition()?.let {
+                val layoutManager = content?.layoutManager as? LinearLayoutManager ?: return
+                layoutManager.findLastVisibleItemPosition().let {
                     println("onScrollStateChanged - newState: $newState, position: $it")
                     pagesStore.dispatchAction(PagesAction.LoadPage(it))
                 }
+                if (newState == RecyclerView.SCROLL_STATE_IDLE) {
+                    val firstVisiblePosition = layoutManager.findFirstVisibleItemPosition()
+                    val firstVisibleView = layoutManager.findViewByPosition(firstVisiblePosition) ?: return
+                    val scrollOffset = -firstVisibleView.top.toFloat() / firstVisibleView.height.coerceAtLeast(1)
+                    pagesStore.dispatchAction(PagesAction.UpdateReadingPosition(firstVisiblePosition, scrollOffset))
+                }
             }
         })
     }
 
     override fun onDestroyView() {
         pagesStore.removeListener(getIdentityId())
+        engineStatusText = null
         super.onDestroyView()
     }
 
@@ -76,9 +101,30 @@ class PagesFragment : ScopeFragment(), PagesStateListener, IdentityId {
         view?.assureMainThread { render(state) }
     }
 
+    override fun newSideEffect(sideEffect: PagesSideEffect) {
+        view?.assureMainThread {
+            val message = when (sideEffect) {
+                is PagesSideEffect.HighlightFailed -> getString(R.string.highlight_failed)
+                is PagesSideEffect.RenderingFailed -> getString(R.string.page_rendering_failed, sideEffect.pageIndex + 1)
+            }
+            Toast.makeText(requireContext(), message, Toast.LENGTH_SHORT).show()
+        }
+    }
+
     private fun render(state: PagesState) {
         println("New pages state: ${Thread.currentThread().name} $state")
+        engineStatusText?.showEngineStatus(state.engineStatus)
+        val readingPosition = state.currentReadingPosition
+        val shouldRestorePosition = !restoredReadingPosition && readingPosition != null && state.currentBookPages.isNotEmpty()
         contentAdapter?.submitList(state.currentBookPages) {
+            if (shouldRestorePosition && readingPosition != null) {
+                restoredReadingPosition = true
+                val pageHeight = content?.getChildAt(0)?.height ?: 0
+                (content?.layoutManager as? LinearLayoutManager)?.scrollToPositionWithOffset(
+                    readingPosition.pageIndex,
+                    -(readingPosition.scrollOffset * pageHeight).toInt()
+                )
+            }
             state.destroy()
         }
     }
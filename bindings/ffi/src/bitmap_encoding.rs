@@ -0,0 +1,32 @@
+use anyhow::Result;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::{ExtendedColorType, ImageEncoder, ImageFormat};
+use crate::domain::ImageEncoding;
+
+const JPEG_QUALITY: u8 = 85;
+
+pub fn encode_rgba(width: i32, height: i32, rgba_bytes: &[u8], encoding: ImageEncoding) -> Result<Vec<u8>> {
+    let (width, height) = (width as u32, height as u32);
+    let mut encoded = vec![];
+    match encoding {
+        ImageEncoding::Png => PngEncoder::new(&mut encoded).write_image(rgba_bytes, width, height, ExtendedColorType::Rgba8)?,
+        // Jpeg has no alpha channel - pages are rendered opaque anyway
+        ImageEncoding::Jpeg => {
+            let rgb_bytes: Vec<u8> = rgba_bytes.chunks_exact(4).flat_map(|pixel| [pixel[0], pixel[1], pixel[2]]).collect();
+            JpegEncoder::new_with_quality(&mut encoded, JPEG_QUALITY).write_image(&rgb_bytes, width, height, ExtendedColorType::Rgb8)?
+        }
+        ImageEncoding::Webp => WebPEncoder::new_lossless(&mut encoded).write_image(rgba_bytes, width, height, ExtendedColorType::Rgba8)?,
+    }
+    Ok(encoded)
+}
+
+pub fn decode_rgba(encoded_bytes: &[u8], encoding: ImageEncoding) -> Result<Vec<u8>> {
+    let format = match encoding {
+        ImageEncoding::Png => ImageFormat::Png,
+        ImageEncoding::Jpeg => ImageFormat::Jpeg,
+        ImageEncoding::Webp => ImageFormat::WebP,
+    };
+    Ok(image::load_from_memory_with_format(encoded_bytes, format)?.into_rgba8().into_raw())
+}
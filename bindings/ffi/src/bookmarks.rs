@@ -0,0 +1,34 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use crate::domain::Bookmark;
+
+const BOOKMARKS_EXPORT_VERSION: u32 = 1;
+
+// Shareable file format - versioned so the notes of older app versions can still be imported
+#[derive(Serialize, Deserialize)]
+struct BookmarksExport {
+    version: u32,
+    bookmarks: Vec<Bookmark>,
+}
+
+pub fn export_bookmarks(bookmarks: &[Bookmark]) -> Result<String> {
+    let export = BookmarksExport { version: BOOKMARKS_EXPORT_VERSION, bookmarks: bookmarks.to_vec() };
+    serde_json::to_string_pretty(&export).context("Serializing bookmarks")
+}
+
+pub fn import_bookmarks(json: &str) -> Result<Vec<Bookmark>> {
+    let export: BookmarksExport = serde_json::from_str(json).context("Parsing bookmarks")?;
+    if export.version > BOOKMARKS_EXPORT_VERSION {
+        bail!("Unsupported bookmarks version {}", export.version)
+    }
+    Ok(export.bookmarks)
+}
+
+// Keeps one bookmark per page, ordered by page - a new note for a bookmarked page replaces the old one
+pub fn merge_bookmarks(bookmarks: &mut Vec<Bookmark>, new_bookmarks: impl IntoIterator<Item=Bookmark>) {
+    for new_bookmark in new_bookmarks {
+        bookmarks.retain(|bookmark| bookmark.page_index != new_bookmark.page_index);
+        bookmarks.push(new_bookmark);
+    }
+    bookmarks.sort_by_key(|bookmark| bookmark.page_index);
+}
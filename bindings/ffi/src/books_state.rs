@@ -1,30 +1,48 @@
 use std::collections::HashMap;
 use std::string::ToString;
 use std::sync::{Arc, Mutex};
-use crate::books_state::BooksResult::BooksListUpdated;
-use crate::domain::{Book};
-use crate::global_state::{GlobalAction, GlobalState, GlobalStateListener, GlobalStore};
+use crate::books_state::BooksResult::{BooksListUpdated, EngineStatusUpdated, LibrarySearchUpdated};
+use crate::domain::{Book, BookMetadataOverlay, EngineStatus, LibrarySearchState, NotesFormat};
+use crate::global_state::{GlobalAction, GlobalSideEffect, GlobalState, GlobalStateListener, GlobalStore};
 
 #[derive(Clone)]
 pub struct BooksState {
     pub some_text: String,
     pub books: Vec<Book>,
+    pub library_search: Option<LibrarySearchState>,
+    pub engine_status: EngineStatus,
 }
 
 #[derive(Clone)]
 pub enum BooksSideEffect {
     OpenFilePicker,
+    SaveFile { file_name: String, mime_type: String, bytes: Vec<u8> },
+    NotesExported { uuid: String, format: NotesFormat, document: String },
+    ExportFailed { uuid: String },
 }
 
 pub enum BooksAction {
     AddClicked,
     MarkPdfLoading { uuid: String },
     LoadPdf { uuid: String, file_name: String, bytes: Vec<u8> },
+    LoadPdfFromPath { uuid: String, file_name: String, path: String },
+    LoadPdfFromFd { uuid: String, file_name: String, fd: i32 },
     MarkPdfLoadingFailed { uuid: String },
+    SubmitPassword { uuid: String, password: String },
+    RetryLoading { uuid: String },
+    BookClicked { uuid: String },
+    ExportAnnotatedPdf { uuid: String },
+    ExportNotes { uuid: String, format: NotesFormat },
+    DeleteBook { uuid: String },
+    RenameBook { uuid: String, title: String },
+    EditBookMetadata { uuid: String, metadata_overlay: BookMetadataOverlay },
+    SearchLibrary { query: String },
 }
 
 pub enum BooksResult {
     BooksListUpdated { books: Vec<Book> },
+    LibrarySearchUpdated { search: Option<LibrarySearchState> },
+    EngineStatusUpdated { engine_status: EngineStatus },
 }
 
 pub trait BooksStateListener: Send + Sync {
@@ -39,17 +57,22 @@ pub struct BooksStore {
     state: Mutex<BooksState>,
     listeners: Mutex<HashMap<String, Box<dyn BooksStateListener>>>,
     // cache
-    last_global_state: Option<GlobalState>,
+    last_global_state: Mutex<Option<GlobalState>>,
 }
 
 impl BooksStore {
     pub fn new(global_store: Arc<GlobalStore>) -> Self {
-        let initial_state = BooksState { some_text: "initial_text".to_string(), books: Vec::new() };
+        let initial_state = BooksState {
+            some_text: "initial_text".to_string(),
+            books: Vec::new(),
+            library_search: None,
+            engine_status: EngineStatus::Initializing,
+        };
         Self {
             global_store: Mutex::new(global_store),
             state: Mutex::new(initial_state),
             listeners: Mutex::new(HashMap::new()),
-            last_global_state: None,
+            last_global_state: Mutex::new(None),
         }
     }
 
@@ -79,11 +102,66 @@ impl BooksStore {
                 .unwrap()
                 .clone()
                 .dispatch_action(GlobalAction::LoadPdf { uuid, file_name, bytes }),
+            BooksAction::LoadPdfFromPath { uuid, file_name, path } => self.global_store
+                .lock()
+                .unwrap()
+                .clone()
+                .dispatch_action(GlobalAction::LoadPdfFromPath { uuid, file_name, path }),
+            BooksAction::LoadPdfFromFd { uuid, file_name, fd } => self.global_store
+                .lock()
+                .unwrap()
+                .clone()
+                .dispatch_action(GlobalAction::LoadPdfFromFd { uuid, file_name, fd }),
             BooksAction::MarkPdfLoadingFailed { uuid } => self.global_store
                 .lock()
                 .unwrap()
                 .clone()
                 .dispatch_action(GlobalAction::MarkPdfLoadingFailed { uuid }),
+            BooksAction::SubmitPassword { uuid, password } => self.global_store
+                .lock()
+                .unwrap()
+                .clone()
+                .dispatch_action(GlobalAction::SubmitPassword { uuid, password }),
+            BooksAction::RetryLoading { uuid } => self.global_store
+                .lock()
+                .unwrap()
+                .clone()
+                .dispatch_action(GlobalAction::RetryLoading { uuid }),
+            BooksAction::BookClicked { uuid } => self.global_store
+                .lock()
+                .unwrap()
+                .clone()
+                .dispatch_action(GlobalAction::OpenBook { uuid }),
+            BooksAction::ExportAnnotatedPdf { uuid } => self.global_store
+                .lock()
+                .unwrap()
+                .clone()
+                .dispatch_action(GlobalAction::ExportAnnotatedPdf { uuid }),
+            BooksAction::ExportNotes { uuid, format } => self.global_store
+                .lock()
+                .unwrap()
+                .clone()
+                .dispatch_action(GlobalAction::ExportNotes { uuid, format }),
+            BooksAction::DeleteBook { uuid } => self.global_store
+                .lock()
+                .unwrap()
+                .clone()
+                .dispatch_action(GlobalAction::DeleteBook { uuid }),
+            BooksAction::RenameBook { uuid, title } => self.global_store
+                .lock()
+                .unwrap()
+                .clone()
+                .dispatch_action(GlobalAction::RenameBook { uuid, title }),
+            BooksAction::EditBookMetadata { uuid, metadata_overlay } => self.global_store
+                .lock()
+                .unwrap()
+                .clone()
+                .dispatch_action(GlobalAction::EditBookMetadata { uuid, metadata_overlay }),
+            BooksAction::SearchLibrary { query } => self.global_store
+                .lock()
+                .unwrap()
+                .clone()
+                .dispatch_action(GlobalAction::SearchLibrary { query }),
         }
     }
 
@@ -103,6 +181,16 @@ impl BooksStore {
                 new_state.books = pdfs;
                 new_state
             }
+            LibrarySearchUpdated { search } => {
+                let mut new_state = state.clone();
+                new_state.library_search = search;
+                new_state
+            }
+            EngineStatusUpdated { engine_status } => {
+                let mut new_state = state.clone();
+                new_state.engine_status = engine_status;
+                new_state
+            }
         }
     }
 
@@ -121,12 +209,38 @@ impl Drop for BooksStore {
 
 impl GlobalStateListener for Arc<BooksStore> {
     fn new_state(&self, state: GlobalState) {
-        let Some(last_global_state) = &self.last_global_state  else {
-            self.clone().process_result(BooksListUpdated { books: state.books.clone() });
-            return;
+        let mut last_global_state = self.last_global_state.lock().unwrap();
+        let (books_changed, library_search_changed, engine_status_changed) = match last_global_state.as_ref() {
+            None => (true, true, true),
+            Some(last_global_state) => (
+                last_global_state.books != state.books,
+                last_global_state.library_search != state.library_search,
+                last_global_state.engine_status != state.engine_status,
+            ),
         };
-        if last_global_state.books != state.books {
+        if books_changed {
             self.clone().process_result(BooksListUpdated { books: state.books.clone() });
         }
+        if library_search_changed {
+            self.clone().process_result(LibrarySearchUpdated { search: state.library_search.clone() });
+        }
+        if engine_status_changed {
+            self.clone().process_result(EngineStatusUpdated { engine_status: state.engine_status.clone() });
+        }
+        *last_global_state = Some(state);
+    }
+
+    fn new_side_effect(&self, side_effect: GlobalSideEffect) {
+        match side_effect {
+            GlobalSideEffect::FileExported { file_name, mime_type, bytes } => {
+                self.dispatch_side_effect(BooksSideEffect::SaveFile { file_name, mime_type, bytes })
+            }
+            GlobalSideEffect::NotesExported { uuid, format, document } => {
+                self.dispatch_side_effect(BooksSideEffect::NotesExported { uuid, format, document })
+            }
+            GlobalSideEffect::ExportFailed { uuid } => self.dispatch_side_effect(BooksSideEffect::ExportFailed { uuid }),
+            // The pages screen reports those
+            GlobalSideEffect::HighlightFailed { .. } | GlobalSideEffect::RenderingFailed { .. } => {}
+        }
     }
 }
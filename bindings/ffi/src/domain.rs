@@ -1,16 +1,49 @@
 use std::sync::Arc;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::bitmap_encoding::{decode_rgba, encode_rgba};
 
 #[derive(Clone, PartialEq)]
 pub struct Book {
     pub uuid: String,
     pub thumbnail: Option<Arc<Bitmap>>,
     pub loading_state: PdfLoadingState,
+    pub metadata_overlay: BookMetadataOverlay,
+}
+
+impl Book {
+    pub fn display_title(&self) -> String {
+        match (&self.metadata_overlay.title, &self.loading_state) {
+            (Some(title), _) => title.clone(),
+            (None, PdfLoadingState::ValidPdf { title, .. }) => title.clone(),
+            (None, _) => self.uuid.clone(),
+        }
+    }
+
+    pub fn display_author(&self) -> String {
+        match (&self.metadata_overlay.author, &self.loading_state) {
+            (Some(author), _) => author.clone(),
+            (None, PdfLoadingState::ValidPdf { author, .. }) => author.clone(),
+            (None, _) => String::new(),
+        }
+    }
+}
+
+// User edits of the metadata read from the pdf - None means the pdf value is used
+#[derive(Clone, PartialEq, Default, Debug, Serialize, Deserialize)]
+pub struct BookMetadataOverlay {
+    pub title: Option<String>,
+    pub author: Option<String>,
 }
 
 #[derive(Clone, PartialEq)]
 pub struct Page {
     pub index: i32,
     pub image: Option<Arc<Bitmap>>,
+    pub quality: RenderQuality,
+    pub highlights: Vec<Highlight>,
+    pub tiles: Vec<Tile>,
 }
 
 impl Page {
@@ -24,9 +57,165 @@ impl Page {
             Some(image) => Some(Arc::clone(image)),
         }
     }
+
+    pub fn quality(&self) -> RenderQuality {
+        self.quality
+    }
+
+    pub fn highlights(&self) -> Vec<Highlight> {
+        self.highlights.clone()
+    }
+
+    pub fn tiles(&self) -> Vec<Tile> {
+        self.tiles.clone()
+    }
 }
 
+// Pages are first rendered as a cheap low resolution preview, then at the full page layout size.
+// Pages without an image count as previews too.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RenderQuality {
+    Preview,
+    Full,
+}
+
+// A part of a page rendered sharper than the page image, rect says which part
 #[derive(Clone, PartialEq)]
+pub struct Tile {
+    pub zoom_level: i32,
+    pub column: i32,
+    pub row: i32,
+    pub rect: PageRect,
+    pub image: Arc<Bitmap>,
+}
+
+// width and height are in density independent units, device_scale is the number of pixels per unit
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Viewport {
+    pub width: f32,
+    pub height: f32,
+    pub zoom: f32,
+    pub device_scale: f32,
+}
+
+impl Viewport {
+    pub fn pixel_width(&self) -> f32 {
+        self.width * self.zoom * self.device_scale
+    }
+
+    pub fn pixel_height(&self) -> f32 {
+        self.height * self.zoom * self.device_scale
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FitMode {
+    Width,
+    Height,
+    WholePage,
+}
+
+// Clockwise, on top of the rotation the pdf itself specifies
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PageRotation {
+    None,
+    Degrees90,
+    Degrees180,
+    Degrees270,
+}
+
+// scroll_offset is the fraction (0.0 - 1.0) of the page that was scrolled past
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ReadingPosition {
+    pub page_index: i32,
+    pub scroll_offset: f32,
+}
+
+// Fractions of the page width / height with the origin in the top left corner,
+// so they stay valid for any render size. Highlight and search result rects are fractions of the page
+// with its own /Rotate applied but not the page layout rotation.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct PageRect {
+    pub left: f32,
+    pub top: f32,
+    pub right: f32,
+    pub bottom: f32,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct SearchResult {
+    pub page_index: i32,
+    pub snippet: String,
+    pub rects: Vec<PageRect>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct SearchState {
+    pub search_id: String,
+    pub query: String,
+    pub results: Vec<SearchResult>,
+    pub is_finished: bool,
+}
+
+// A selection dragged over a page displayed at view_width x view_height, in view pixels
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PageSelection {
+    pub view_width: f32,
+    pub view_height: f32,
+    pub start_x: f32,
+    pub start_y: f32,
+    pub end_x: f32,
+    pub end_y: f32,
+}
+
+// color is 0xAARRGGBB, like the page bitmaps
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Highlight {
+    pub highlight_id: String,
+    pub page_index: i32,
+    pub first_char_index: i32,
+    pub char_count: i32,
+    pub color: u32,
+    pub note: String,
+    pub quoted_text: String,
+    pub rects: Vec<PageRect>,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Bookmark {
+    pub page_index: i32,
+    pub note: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum NotesFormat {
+    Markdown,
+    Json,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct OutlineEntry {
+    pub title: String,
+    pub page_index: Option<i32>,
+    pub children: Vec<OutlineEntry>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct LibrarySearchHit {
+    pub book_uuid: String,
+    pub page_index: i32,
+    pub snippet: String,
+    pub score: f32,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct LibrarySearchState {
+    pub query: String,
+    pub hits: Vec<LibrarySearchHit>,
+}
+
+#[derive(Clone, PartialEq)]
+#[allow(clippy::enum_variant_names)]
 pub enum PdfLoadingState {
     LoadingPdf,
     ValidPdf {
@@ -35,33 +224,136 @@ pub enum PdfLoadingState {
         thumbnail: Option<Arc<Bitmap>>,
         page_count: i32,
     },
-    ErrorPdf,
+    // Encrypted - wrong_password is set once a password was tried and rejected
+    PasswordRequired { file_name: String, wrong_password: bool },
+    ErrorPdf { error: PdfLoadingError },
+}
+
+// Whether pdfium could be loaded - without it no book opens, the rest of the library still works
+#[derive(Clone, PartialEq, Debug)]
+pub enum EngineStatus {
+    Initializing,
+    Ready,
+    Unavailable { reason: String },
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PdfLoadingError {
+    CorruptFile,
+    UnsupportedFormat,
+    // Encrypted in a way that no password opens
+    PasswordProtected,
+    Io,
+    // The host couldn't open the file, so there's no copy of it to load again
+    SourceUnavailable,
+    EngineUnavailable,
+    OutOfMemory,
+}
+
+impl PdfLoadingError {
+    // Whether loading the same source again might work - others fail no matter how often it's retried.
+    // Pdfium is only bound once, so an unavailable engine stays unavailable until the app restarts.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, PdfLoadingError::Io | PdfLoadingError::OutOfMemory)
+    }
+
+    // Whether the source itself can't be loaded, so there's no point in keeping it
+    pub fn is_source_error(&self) -> bool {
+        matches!(
+            self,
+            PdfLoadingError::CorruptFile
+                | PdfLoadingError::UnsupportedFormat
+                | PdfLoadingError::PasswordProtected
+                | PdfLoadingError::SourceUnavailable
+        )
+    }
+}
+
+// Byte order of the bitmap pixels in memory
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PixelFormat {
+    // A native endian u32 0xAARRGGBB per pixel
+    Argb,
+    // R, G, B, A bytes - what pdfium renders and what Android ARGB_8888 bitmaps hold
+    Rgba,
+}
+
+// Compressed forms a bitmap can be held in and handed to the host
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ImageEncoding {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+enum BitmapData {
+    Pixels(Vec<u8>),
+    // Never decoded for good, so it only ever holds the compressed bytes the page cache counts
+    Encoded { encoding: ImageEncoding, bytes: Vec<u8> },
 }
 
 pub struct Bitmap {
     pub width: i32,
     pub height: i32,
     pub uid: String,
-    pub pixels: Vec<u32>,
+    pub pixel_format: PixelFormat,
+    data: BitmapData,
 }
 
 impl Bitmap {
-    pub fn new(
+    // Takes the RGBA bytes pdfium renders, swizzled in place when another pixel format is wanted
+    pub fn from_rgba(
+        width: i32,
+        height: i32,
+        mut rgba_bytes: Vec<u8>,
+        pixel_format: PixelFormat,
+    ) -> Arc<Bitmap> {
+        if pixel_format == PixelFormat::Argb {
+            rgba_to_argb(&mut rgba_bytes);
+        }
+        Arc::new(
+            Bitmap {
+                width,
+                height,
+                uid: Uuid::new_v4().to_string(),
+                pixel_format,
+                data: BitmapData::Pixels(rgba_bytes),
+            }
+        )
+    }
+
+    pub fn from_encoded(
         width: i32,
         height: i32,
-        uid: String,
-        pixels: Vec<u32>,
+        bytes: Vec<u8>,
+        encoding: ImageEncoding,
+        pixel_format: PixelFormat,
     ) -> Arc<Bitmap> {
         Arc::new(
             Bitmap {
                 width,
                 height,
-                uid,
-                pixels,
+                uid: Uuid::new_v4().to_string(),
+                pixel_format,
+                data: BitmapData::Encoded { encoding, bytes },
             }
         )
     }
 
+    // The same image (uid included) held compressed - the pixels are decoded again only if asked for
+    pub fn encode(&self, encoding: ImageEncoding) -> Result<Arc<Bitmap>> {
+        let bytes = self.try_encoded_bytes(encoding)?;
+        Ok(Arc::new(
+            Bitmap {
+                width: self.width,
+                height: self.height,
+                uid: self.uid.clone(),
+                pixel_format: self.pixel_format,
+                data: BitmapData::Encoded { encoding, bytes },
+            }
+        ))
+    }
+
     pub fn width(&self) -> i32 {
         self.width
     }
@@ -74,8 +366,114 @@ impl Bitmap {
         self.uid.clone()
     }
 
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
+    }
+
+    pub fn encoding(&self) -> Option<ImageEncoding> {
+        match &self.data {
+            BitmapData::Pixels(_) => None,
+            BitmapData::Encoded { encoding, .. } => Some(*encoding),
+        }
+    }
+
+    // Compressed image for the host's own decoder, encoded on the spot unless the bitmap is held in that encoding
+    pub fn encoded_bytes(&self, encoding: ImageEncoding) -> Vec<u8> {
+        self.try_encoded_bytes(encoding).unwrap_or_else(|error| {
+            error!("Bitmap::encoded_bytes error - {error}");
+            vec![]
+        })
+    }
+
+    pub fn try_encoded_bytes(&self, encoding: ImageEncoding) -> Result<Vec<u8>> {
+        match &self.data {
+            BitmapData::Encoded { encoding: current_encoding, bytes, .. } if *current_encoding == encoding => Ok(bytes.clone()),
+            _ => encode_rgba(self.width, self.height, &self.rgba_bytes()?, encoding),
+        }
+    }
+
+    // Memory taken by the bitmap
+    pub fn held_bytes(&self) -> u64 {
+        match &self.data {
+            BitmapData::Pixels(pixels) => pixels.len() as u64,
+            BitmapData::Encoded { bytes, .. } => bytes.len() as u64,
+        }
+    }
+
+    // Size of the pixels behind pixels_address
+    pub fn byte_count(&self) -> u64 {
+        self.width as u64 * self.height as u64 * 4
+    }
+
+    // Address of the first pixel byte, so the host can read the pixels in place instead of copying them
+    // through the bindings. Only valid as long as the host holds on to this bitmap.
+    // Encoded bitmaps have no pixels in memory and give 0 - the host decodes their encoded_bytes instead.
+    pub fn pixels_address(&self) -> u64 {
+        match &self.data {
+            BitmapData::Pixels(pixels) => pixels.as_ptr() as u64,
+            BitmapData::Encoded { .. } => {
+                error!("Bitmap::pixels_address error - bitmap {} is encoded", self.uid);
+                0
+            }
+        }
+    }
+
+    // 0xAARRGGBB pixels whatever the pixel format, decoded into the copy for encoded bitmaps
     pub fn copy_pixels(&self) -> Vec<u32> {
-        self.pixels.clone()
+        let pixels = self.with_pixels(|pixels| {
+            pixels
+                .chunks_exact(4)
+                .map(|pixel| match self.pixel_format {
+                    PixelFormat::Argb => u32::from_ne_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]),
+                    PixelFormat::Rgba => u32::from_be_bytes([pixel[3], pixel[0], pixel[1], pixel[2]]),
+                })
+                .collect()
+        });
+        pixels.unwrap_or_else(|error| {
+            error!("Bitmap::copy_pixels error - {error}");
+            vec![]
+        })
+    }
+
+    // Encoded bitmaps are decoded just for the block, the pixels aren't kept
+    fn with_pixels<R>(&self, block: impl FnOnce(&[u8]) -> R) -> Result<R> {
+        match &self.data {
+            BitmapData::Pixels(pixels) => Ok(block(pixels)),
+            BitmapData::Encoded { encoding, bytes } => {
+                let mut decoded = decode_rgba(bytes, *encoding)?;
+                if self.pixel_format == PixelFormat::Argb {
+                    rgba_to_argb(&mut decoded);
+                }
+                Ok(block(&decoded))
+            }
+        }
+    }
+
+    fn rgba_bytes(&self) -> Result<Vec<u8>> {
+        match &self.data {
+            BitmapData::Pixels(pixels) => {
+                let mut rgba_bytes = pixels.clone();
+                if self.pixel_format == PixelFormat::Argb {
+                    argb_to_rgba(&mut rgba_bytes);
+                }
+                Ok(rgba_bytes)
+            }
+            BitmapData::Encoded { encoding, bytes } => decode_rgba(bytes, *encoding),
+        }
+    }
+}
+
+fn rgba_to_argb(bytes: &mut [u8]) {
+    for pixel in bytes.chunks_exact_mut(4) {
+        let argb = u32::from_be_bytes([pixel[3], pixel[0], pixel[1], pixel[2]]);
+        pixel.copy_from_slice(&argb.to_ne_bytes());
+    }
+}
+
+fn argb_to_rgba(bytes: &mut [u8]) {
+    for pixel in bytes.chunks_exact_mut(4) {
+        let [a, r, g, b] = u32::from_ne_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]).to_be_bytes();
+        pixel.copy_from_slice(&[r, g, b, a]);
     }
 }
 
@@ -92,7 +490,8 @@ impl Clone for Bitmap {
             width: self.width,
             height: self.height,
             uid: self.uid.clone(),
-            pixels: vec![],
+            pixel_format: self.pixel_format,
+            data: BitmapData::Pixels(vec![]),
         }
     }
 }
@@ -1,7 +1,10 @@
+// Generated scaffolding code is not ours to lint
+#![allow(clippy::all)]
+
 uniffi_macros::include_scaffolding!("global_bindings");
 
 use crate::books_state::{BooksAction, BooksSideEffect, BooksState, BooksStateListener, BooksStore};
-use crate::domain::{Bitmap, Book, Page, PdfLoadingState};
-use crate::global_state::{GlobalAction, GlobalState, GlobalStateListener, GlobalStore};
-use crate::pages_state::{PagesAction, PagesState, PagesStateListener, PagesStore};
+use crate::domain::{Bitmap, Book, Bookmark, BookMetadataOverlay, EngineStatus, FitMode, Highlight, ImageEncoding, LibrarySearchHit, LibrarySearchState, NotesFormat, OutlineEntry, Page, PageRect, PageRotation, PageSelection, PdfLoadingError, PdfLoadingState, PixelFormat, ReadingPosition, RenderQuality, SearchResult, SearchState, Tile, Viewport};
+use crate::global_state::{GlobalAction, GlobalSideEffect, GlobalState, GlobalStateListener, GlobalStore, PasswordVault};
+use crate::pages_state::{PagesAction, PagesSideEffect, PagesState, PagesStateListener, PagesStore};
 use crate::pdfium_manager::generate_pdf_uuid;
@@ -1,7 +1,10 @@
+use std::cmp::max;
 use std::collections::HashMap;
+use std::fs::File;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{channel, Receiver, Sender};
-use pdfium_render::prelude::*;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
 
 #[cfg(target_os = "android")]
 use android_logger::Config;
@@ -10,10 +13,20 @@ use log::LevelFilter;
 
 use std::thread;
 use std::thread::JoinHandle;
-use anyhow::{Context, Result};
-use crate::domain::{Bitmap, Book, Page, PdfLoadingState};
-use crate::pdfium_manager::{PdfiumAction, PdfiumManager};
+use anyhow::{bail, Context, Result};
+use uuid::Uuid;
+use crate::bookmarks::{import_bookmarks, merge_bookmarks};
+use crate::domain::{Bitmap, Book, Bookmark, BookMetadataOverlay, EngineStatus, FitMode, Highlight, LibrarySearchState, NotesFormat, OutlineEntry, Page, PageRect, PageRotation, PageSelection, PdfLoadingError, PdfLoadingState, PixelFormat, ReadingPosition, RenderQuality, SearchResult, SearchState, Tile, Viewport};
+use crate::library_index::LibraryIndex;
+use crate::library_storage::{LibraryStorage, StoredBook};
+use crate::notes_export::export_notes;
+use crate::page_tiles::MAX_TILE_ZOOM_LEVEL;
+use crate::page_layout::PageLayout;
+use crate::pdfium_manager::{loading_error, EngineUnavailable, PdfiumAction, PdfiumManager, DEFAULT_PAGE_LAYOUT};
+use crate::render_disk_cache::remove_document_renders;
 
+// Reading positions change on every scroll, so the annotations are saved once they've settled for this long
+const ANNOTATIONS_SAVE_DELAY: Duration = Duration::from_millis(500);
 
 #[derive(Clone)]
 pub struct GlobalState {
@@ -21,32 +34,142 @@ pub struct GlobalState {
     pub books: Vec<Book>,
     pub current_book: Option<Book>,
     pub current_book_pages: Vec<Arc<Page>>,
+    pub current_book_outline: Vec<OutlineEntry>,
+    pub viewport: Option<Viewport>,
+    pub fit_mode: FitMode,
+    pub page_rotation: PageRotation,
+    pub reading_positions: HashMap<String, ReadingPosition>,
+    pub bookmarks: HashMap<String, Vec<Bookmark>>,
+    pub highlights: HashMap<String, Vec<Highlight>>,
+    pub current_search: Option<SearchState>,
+    pub library_search: Option<LibrarySearchState>,
+    pub engine_status: EngineStatus,
+}
+
+impl GlobalState {
+    pub fn current_reading_position(&self) -> Option<ReadingPosition> {
+        let current_book = self.current_book.as_ref()?;
+        self.reading_positions.get(&current_book.uuid).copied()
+    }
+
+    pub fn current_bookmarks(&self) -> Vec<Bookmark> {
+        self.current_book
+            .as_ref()
+            .and_then(|book| self.bookmarks.get(&book.uuid))
+            .cloned()
+            .unwrap_or_default()
+    }
 }
 
 pub enum GlobalAction {
     MarkPdfLoading { uuid: String },
     LoadPdf { uuid: String, file_name: String, bytes: Vec<u8> },
+    LoadPdfFromPath { uuid: String, file_name: String, path: String },
+    // The store takes ownership of fd and closes it once the pdf is copied
+    LoadPdfFromFd { uuid: String, file_name: String, fd: i32 },
     MarkPdfLoadingFailed { uuid: String },
+    SubmitPassword { uuid: String, password: String },
+    RetryLoading { uuid: String },
     LoadPage { page_index: i32 },
+    GoToPage { page_index: i32 },
+    SetViewport { viewport: Viewport },
+    SetPageLayout { fit_mode: FitMode, rotation: PageRotation },
+    LoadTiles { page_index: i32, zoom_level: i32, region: PageRect },
+    UpdateReadingPosition { page_index: i32, scroll_offset: f32 },
+    AddBookmark { page_index: i32, note: String },
+    RemoveBookmark { page_index: i32 },
+    ImportBookmarks { json: String },
+    AddHighlight { page_index: i32, selection: PageSelection, color: u32, note: String },
+    RemoveHighlight { highlight_id: String },
+    EditHighlightNote { highlight_id: String, note: String },
+    OpenBook { uuid: String },
+    ExportAnnotatedPdf { uuid: String },
+    ExportNotes { uuid: String, format: NotesFormat },
+    DeleteBook { uuid: String },
+    RenameBook { uuid: String, title: String },
+    EditBookMetadata { uuid: String, metadata_overlay: BookMetadataOverlay },
+    Search { query: String },
+    CancelSearch,
+    SearchLibrary { query: String },
+    ClearCache,
 }
 
 pub enum GlobalResult {
+    LibraryRestored {
+        books: Vec<Book>,
+        reading_positions: HashMap<String, ReadingPosition>,
+        bookmarks: HashMap<String, Vec<Bookmark>>,
+        highlights: HashMap<String, Vec<Highlight>>,
+    },
     PdfLoading { uuid: String },
-    PdfLoadingFailed { uuid: String },
+    PdfLoadingFailed { uuid: String, error: PdfLoadingError },
+    // file_name is None for books of the library, which keep their title
+    PasswordRequired { uuid: String, file_name: Option<String>, wrong_password: bool },
+    PdfReloading { uuid: String },
     PdfLoaded {
         id: String,
         title: String,
         author: String,
         thumbnail: Option<Arc<Bitmap>>,
         page_count: i32,
+        outline: Vec<OutlineEntry>,
+    },
+    BookOpened {
+        uuid: String,
+        thumbnail: Option<Arc<Bitmap>>,
+        page_count: i32,
+        outline: Vec<OutlineEntry>,
     },
     PagesLoaded {
         pages: Vec<Arc<Page>>,
     },
+    PagesEvicted { page_indices: Vec<i32> },
+    TilesLoaded { page_index: i32, tiles: Vec<Tile> },
+    ReadingPositionUpdated { page_index: i32, scroll_offset: f32 },
+    ViewportChanged { viewport: Viewport },
+    PageLayoutChanged { fit_mode: FitMode, rotation: PageRotation },
+    BookmarksAdded { bookmarks: Vec<Bookmark> },
+    BookmarkRemoved { page_index: i32 },
+    HighlightCreated { uuid: String, highlight: Highlight },
+    HighlightRemoved { highlight_id: String },
+    HighlightNoteEdited { highlight_id: String, note: String },
+    BookDeleted { uuid: String },
+    BookRenamed { uuid: String, title: String },
+    BookMetadataEdited { uuid: String, metadata_overlay: BookMetadataOverlay },
+    SearchStarted { search_id: String, query: String },
+    SearchResultsFound { search_id: String, results: Vec<SearchResult> },
+    SearchFinished { search_id: String },
+    SearchCancelled,
+    BookTextExtracted { uuid: String, page_texts: Vec<String> },
+    LibrarySearchFinished { search: Option<LibrarySearchState> },
+    FileExported { file_name: String, mime_type: String, bytes: Vec<u8> },
+    EngineStatusChanged { status: EngineStatus },
+    // Failures of actions that would otherwise leave the host waiting
+    HighlightFailed { page_index: i32 },
+    RenderingFailed { page_index: i32 },
+    ExportFailed { uuid: String },
+    // Handled by the worker thread, which saves the unsaved annotations once they've settled
+    AnnotationsChanged,
+}
+
+#[derive(Clone)]
+pub enum GlobalSideEffect {
+    FileExported { file_name: String, mime_type: String, bytes: Vec<u8> },
+    NotesExported { uuid: String, format: NotesFormat, document: String },
+    HighlightFailed { page_index: i32 },
+    RenderingFailed { page_index: i32 },
+    ExportFailed { uuid: String },
 }
 
 pub trait GlobalStateListener: Send + Sync {
     fn new_state(&self, state: GlobalState);
+    fn new_side_effect(&self, side_effect: GlobalSideEffect);
+}
+
+pub trait PasswordVault: Send + Sync {
+    fn save_password(&self, uuid: String, password: String);
+    fn load_password(&self, uuid: String) -> Option<String>;
+    fn remove_password(&self, uuid: String);
 }
 
 pub trait GlobalDispatch {
@@ -58,15 +181,44 @@ pub struct GlobalStore {
     listeners: Mutex<HashMap<String, Box<dyn GlobalStateListener>>>,
     pdfium_manager: Mutex<Option<PdfiumManager>>,
     worker_thread_manager: Mutex<Option<WorkerThreadManager>>,
+    library_storage: LibraryStorage,
+    // What the library file holds, None until it's restored - a library that failed to load isn't overwritten
+    stored_books: Mutex<Option<Vec<StoredBook>>>,
+    // None until it's loaded on the worker thread
+    library_index: Mutex<Option<LibraryIndex>>,
+    // Passwords of the encrypted books opened this session
+    passwords: Mutex<HashMap<String, String>>,
+    password_vault: Mutex<Option<Box<dyn PasswordVault>>>,
+    // Mirrors the state's, which can't be locked while sending pdfium actions from the reducer
+    engine_status: Mutex<EngineStatus>,
+    unsaved_annotations: Mutex<UnsavedAnnotations>,
+}
+
+// Which annotations changed since they were last saved
+#[derive(Default)]
+struct UnsavedAnnotations {
+    reading_positions: bool,
+    bookmarks: bool,
+    highlights: bool,
 }
 
 impl GlobalStore {
-    pub fn new() -> Self {
+    pub fn new(storage_dir: String) -> Self {
         let initial_state = GlobalState {
             some_text: "initial_text".to_string(),
             books: Vec::new(),
             current_book: None,
             current_book_pages: vec![],
+            current_book_outline: vec![],
+            viewport: None,
+            fit_mode: DEFAULT_PAGE_LAYOUT.fit_mode,
+            page_rotation: DEFAULT_PAGE_LAYOUT.rotation,
+            reading_positions: HashMap::new(),
+            bookmarks: HashMap::new(),
+            highlights: HashMap::new(),
+            current_search: None,
+            library_search: None,
+            engine_status: EngineStatus::Initializing,
         };
         #[cfg(target_os = "android")]
         android_logger::init_once(Config::default().with_max_level(LevelFilter::Trace));
@@ -75,16 +227,95 @@ impl GlobalStore {
             listeners: Mutex::new(HashMap::new()),
             pdfium_manager: Mutex::new(None),
             worker_thread_manager: Mutex::new(None),
+            library_storage: LibraryStorage::new(storage_dir),
+            stored_books: Mutex::new(None),
+            library_index: Mutex::new(None),
+            passwords: Mutex::new(HashMap::new()),
+            password_vault: Mutex::new(None),
+            engine_status: Mutex::new(EngineStatus::Initializing),
+            unsaved_annotations: Mutex::new(UnsavedAnnotations::default()),
         }
     }
 
-    pub fn init(self: Arc<Self>) {
-        let worker_thread_manager = Self::init_worker_thread(self.clone());
-        let pdfium_manager = PdfiumManager::new(worker_thread_manager.global_action_sender.clone());
-        let mut pdfium_manager_reference = self.pdfium_manager.lock().unwrap();
-        *pdfium_manager_reference = Some(pdfium_manager);
-        let mut worker_thread_manager_reference = self.worker_thread_manager.lock().unwrap();
-        *worker_thread_manager_reference = Some(worker_thread_manager)
+    // page_cache_bytes bounds the memory taken by the rendered page images of the open book,
+    // pixel_format is the one the host displays without converting,
+    // library_search_paths are the directories to look for the pdfium library in before the system ones
+    pub fn init(
+        self: Arc<Self>,
+        page_cache_bytes: u64,
+        pixel_format: PixelFormat,
+        library_search_paths: Vec<String>,
+    ) {
+        // The books come first, the worker thread tells the books deleted while it loads the index by them
+        self.clone().process_result(self.restore_library());
+        let (action_sender, action_receiver): (Sender<GlobalResult>, Receiver<GlobalResult>) = channel();
+        let global_action_sender = Arc::new(Mutex::new(action_sender));
+        // The worker thread queues indexing as soon as it starts, so pdfium has to be there before it
+        let pdfium_manager = PdfiumManager::new(
+            global_action_sender.clone(),
+            page_cache_bytes,
+            pixel_format,
+            self.library_storage.render_cache_directory(),
+            library_search_paths.into_iter().map(PathBuf::from).collect(),
+        );
+        *self.pdfium_manager.lock().unwrap() = Some(pdfium_manager);
+        let worker_thread_manager = Self::init_worker_thread(self.clone(), global_action_sender, action_receiver);
+        *self.worker_thread_manager.lock().unwrap() = Some(worker_thread_manager);
+    }
+
+    // Every file is restored on its own, so one that fails to load doesn't take the others with it
+    fn restore_library(&self) -> GlobalResult {
+        let books = match self.library_storage.load_books() {
+            Ok(stored_books) => {
+                if let Err(error) = self.library_storage.remove_orphaned_sources(&stored_books) {
+                    error!("GlobalStore::restore_library - removing orphaned sources failed - {error}")
+                }
+                let books = stored_books.iter().map(StoredBook::to_book).collect();
+                *self.stored_books.lock().unwrap() = Some(stored_books);
+                books
+            }
+            Err(error) => {
+                error!("GlobalStore::restore_library - loading books failed, the library won't be saved - {error}");
+                vec![]
+            }
+        };
+        let reading_positions = self.library_storage.load_reading_positions().unwrap_or_else(|error| {
+            error!("GlobalStore::restore_library - loading reading positions failed - {error}");
+            HashMap::new()
+        });
+        let bookmarks = self.library_storage.load_bookmarks().unwrap_or_else(|error| {
+            error!("GlobalStore::restore_library - loading bookmarks failed - {error}");
+            HashMap::new()
+        });
+        let highlights = self.library_storage.load_highlights().unwrap_or_else(|error| {
+            error!("GlobalStore::restore_library - loading highlights failed - {error}");
+            HashMap::new()
+        });
+        GlobalResult::LibraryRestored { books, reading_positions, bookmarks, highlights }
+    }
+
+    // Parsing and tokenizing the text of the whole library takes a while, so it's done on the worker thread
+    // rather than in init
+    fn restore_library_index(&self) {
+        let page_texts = self.library_storage.load_library_index().unwrap_or_else(|error| {
+            error!("GlobalStore::restore_library_index - loading library index failed - {error}");
+            HashMap::new()
+        });
+        let mut library_index = LibraryIndex::from_page_texts(page_texts);
+        let state = self.state.lock().unwrap();
+        // Books deleted while the index was loading
+        let deleted_book_uuids: Vec<String> = library_index
+            .book_uuids()
+            .filter(|uuid| !state.books.iter().any(|book| &book.uuid == *uuid))
+            .cloned()
+            .collect();
+        for uuid in deleted_book_uuids {
+            library_index.remove_book(&uuid);
+        }
+        *self.library_index.lock().unwrap() = Some(library_index);
+        let unindexed_book_uuids = self.unindexed_books(&[], &state.books);
+        drop(state);
+        self.index_books(unindexed_book_uuids);
     }
 
     pub fn add_listener(&self, id: String, state_listener: Box<dyn GlobalStateListener>) {
@@ -96,32 +327,319 @@ impl GlobalStore {
         self.listeners.lock().unwrap().remove(&id);
     }
 
+    // Without a vault passwords are only remembered until the app is closed
+    pub fn set_password_vault(&self, vault: Box<dyn PasswordVault>) {
+        *self.password_vault.lock().unwrap() = Some(vault);
+    }
+
     pub fn dispatch_action(self: Arc<Self>, action: GlobalAction) {
         match action {
             GlobalAction::MarkPdfLoading { uuid } => self.process_result(GlobalResult::PdfLoading { uuid }),
-            GlobalAction::LoadPdf { uuid, file_name, bytes } => match self.load_pdf(uuid, file_name, bytes) {
+            GlobalAction::LoadPdf { uuid, file_name, bytes } => match self.load_pdf(&uuid, file_name, bytes) {
+                Ok(_) => {}
+                Err(error) => {
+                    error!("GlobalAction::LoadPdf error - {error}");
+                    self.process_result(GlobalResult::PdfLoadingFailed { uuid, error: loading_error(&error) })
+                }
+            }
+            GlobalAction::LoadPdfFromPath { uuid, file_name, path } => match self.load_pdf_from_path(&uuid, file_name, path) {
+                Ok(_) => {}
+                Err(error) => {
+                    error!("GlobalAction::LoadPdfFromPath error - {error}");
+                    self.process_result(GlobalResult::PdfLoadingFailed { uuid, error: loading_error(&error) })
+                }
+            }
+            GlobalAction::LoadPdfFromFd { uuid, file_name, fd } => match self.load_pdf_from_fd(&uuid, file_name, fd) {
                 Ok(_) => {}
-                Err(error) => { error!("GlobalAction::LoadPdf error - {error}") }
+                Err(error) => {
+                    error!("GlobalAction::LoadPdfFromFd error - {error}");
+                    self.process_result(GlobalResult::PdfLoadingFailed { uuid, error: loading_error(&error) })
+                }
+            }
+            GlobalAction::MarkPdfLoadingFailed { uuid } => self.process_result(
+                GlobalResult::PdfLoadingFailed { uuid, error: PdfLoadingError::SourceUnavailable }
+            ),
+            GlobalAction::SubmitPassword { uuid, password } => match self.clone().submit_password(uuid, password) {
+                Ok(_) => {}
+                Err(error) => { error!("GlobalAction::SubmitPassword error - {error}") }
+            }
+            GlobalAction::RetryLoading { uuid } => match self.clone().retry_loading(uuid) {
+                Ok(_) => {}
+                Err(error) => { error!("GlobalAction::RetryLoading error - {error}") }
             }
-            GlobalAction::MarkPdfLoadingFailed { uuid } => self.process_result(GlobalResult::PdfLoadingFailed { uuid }),
             GlobalAction::LoadPage { page_index } => match self.load_page(page_index) {
                 Ok(_) => {}
-                Err(error) => { error!("GlobalAction::LoadPage error - {error}") }
+                Err(error) => {
+                    error!("GlobalAction::LoadPage error - {error}");
+                    self.process_result(GlobalResult::RenderingFailed { page_index })
+                }
+            }
+            GlobalAction::GoToPage { page_index } => match self.clone().go_to_page(page_index) {
+                Ok(_) => {}
+                Err(error) => {
+                    error!("GlobalAction::GoToPage error - {error}");
+                    self.process_result(GlobalResult::RenderingFailed { page_index })
+                }
+            }
+            GlobalAction::LoadTiles { page_index, zoom_level, region } => match self.load_tiles(page_index, zoom_level, region) {
+                Ok(_) => {}
+                Err(error) => {
+                    error!("GlobalAction::LoadTiles error - {error}");
+                    self.process_result(GlobalResult::RenderingFailed { page_index })
+                }
+            }
+            GlobalAction::SetViewport { viewport } => match self.set_viewport(viewport) {
+                Ok(_) => {}
+                Err(error) => { error!("GlobalAction::SetViewport error - {error}") }
+            }
+            GlobalAction::SetPageLayout { fit_mode, rotation } => match self.update_page_layout(
+                GlobalResult::PageLayoutChanged { fit_mode, rotation }
+            ) {
+                Ok(_) => {}
+                Err(error) => { error!("GlobalAction::SetPageLayout error - {error}") }
+            }
+            GlobalAction::UpdateReadingPosition { page_index, scroll_offset } => self.process_result(
+                GlobalResult::ReadingPositionUpdated { page_index, scroll_offset }
+            ),
+            GlobalAction::AddBookmark { page_index, note } => self.process_result(
+                GlobalResult::BookmarksAdded { bookmarks: vec![Bookmark { page_index, note }] }
+            ),
+            GlobalAction::RemoveBookmark { page_index } => self.process_result(GlobalResult::BookmarkRemoved { page_index }),
+            GlobalAction::ImportBookmarks { json } => match import_bookmarks(&json) {
+                Ok(bookmarks) => self.process_result(GlobalResult::BookmarksAdded { bookmarks }),
+                Err(error) => { error!("GlobalAction::ImportBookmarks error - {error}") }
+            }
+            GlobalAction::AddHighlight { page_index, selection, color, note } => {
+                match self.add_highlight(page_index, selection, color, note) {
+                    Ok(_) => {}
+                    Err(error) => {
+                        error!("GlobalAction::AddHighlight error - {error}");
+                        self.process_result(GlobalResult::HighlightFailed { page_index })
+                    }
+                }
+            }
+            GlobalAction::RemoveHighlight { highlight_id } => self.process_result(GlobalResult::HighlightRemoved { highlight_id }),
+            GlobalAction::EditHighlightNote { highlight_id, note } => self.process_result(
+                GlobalResult::HighlightNoteEdited { highlight_id, note }
+            ),
+            GlobalAction::OpenBook { uuid } => match self.open_book(uuid) {
+                Ok(_) => {}
+                Err(error) => { error!("GlobalAction::OpenBook error - {error}") }
+            }
+            GlobalAction::ExportAnnotatedPdf { uuid } => match self.export_annotated_pdf(uuid.clone()) {
+                Ok(_) => {}
+                Err(error) => {
+                    error!("GlobalAction::ExportAnnotatedPdf error - {error}");
+                    self.process_result(GlobalResult::ExportFailed { uuid })
+                }
+            }
+            GlobalAction::ExportNotes { uuid, format } => match self.export_notes(uuid, format) {
+                Ok(_) => {}
+                Err(error) => { error!("GlobalAction::ExportNotes error - {error}") }
+            }
+            GlobalAction::DeleteBook { uuid } => match self.delete_book(uuid) {
+                Ok(_) => {}
+                Err(error) => { error!("GlobalAction::DeleteBook error - {error}") }
+            }
+            GlobalAction::RenameBook { uuid, title } => self.process_result(GlobalResult::BookRenamed { uuid, title }),
+            GlobalAction::EditBookMetadata { uuid, metadata_overlay } => self.process_result(
+                GlobalResult::BookMetadataEdited { uuid, metadata_overlay }
+            ),
+            GlobalAction::Search { query } => match self.search(query) {
+                Ok(_) => {}
+                Err(error) => { error!("GlobalAction::Search error - {error}") }
+            }
+            GlobalAction::CancelSearch => match self.cancel_search() {
+                Ok(_) => {}
+                Err(error) => { error!("GlobalAction::CancelSearch error - {error}") }
+            }
+            GlobalAction::SearchLibrary { query } => self.search_library(query),
+            GlobalAction::ClearCache => match self.send_pdfium_action(PdfiumAction::ClearCache) {
+                Ok(_) => {}
+                Err(error) => { error!("GlobalAction::ClearCache error - {error}") }
             }
         };
     }
 
     pub fn process_result(self: Arc<Self>, action: GlobalResult) {
+        // The library index is too big to live in the state that's cloned to every listener
+        let action = match action {
+            GlobalResult::BookTextExtracted { uuid, page_texts } => return self.update_library_index(uuid, page_texts),
+            // The worker thread holds these back until the annotations settle, they only get here without one
+            GlobalResult::AnnotationsChanged => return self.save_annotations(),
+            GlobalResult::FileExported { file_name, mime_type, bytes } => {
+                return self.dispatch_side_effect(GlobalSideEffect::FileExported { file_name, mime_type, bytes });
+            }
+            GlobalResult::HighlightFailed { page_index } => {
+                return self.dispatch_side_effect(GlobalSideEffect::HighlightFailed { page_index });
+            }
+            GlobalResult::RenderingFailed { page_index } => {
+                return self.dispatch_side_effect(GlobalSideEffect::RenderingFailed { page_index });
+            }
+            GlobalResult::ExportFailed { uuid } => return self.dispatch_side_effect(GlobalSideEffect::ExportFailed { uuid }),
+            GlobalResult::EngineStatusChanged { status } => {
+                *self.engine_status.lock().unwrap() = status.clone();
+                GlobalResult::EngineStatusChanged { status }
+            }
+            GlobalResult::PasswordRequired { uuid, file_name, wrong_password } => {
+                if wrong_password {
+                    self.forget_password(&uuid);
+                }
+                GlobalResult::PasswordRequired { uuid, file_name, wrong_password }
+            }
+            action => action,
+        };
         let mut state = self.state.lock().unwrap();
-        let new_state = Self::reduce(state.clone(), action);
+        let mut new_state = Self::reduce(state.clone(), action);
+        Self::attach_highlights(&mut new_state);
+        if new_state.books != state.books {
+            self.persist_library(&new_state.books);
+        }
+        let annotations_changed = {
+            let mut unsaved_annotations = self.unsaved_annotations.lock().unwrap();
+            unsaved_annotations.reading_positions |= new_state.reading_positions != state.reading_positions;
+            unsaved_annotations.bookmarks |= new_state.bookmarks != state.bookmarks;
+            unsaved_annotations.highlights |= new_state.highlights != state.highlights;
+            new_state.reading_positions != state.reading_positions
+                || new_state.bookmarks != state.bookmarks
+                || new_state.highlights != state.highlights
+        };
+        let opened_book = new_state.current_book.as_ref().map(|book| &book.uuid);
+        if opened_book.is_some() && opened_book != state.current_book.as_ref().map(|book| &book.uuid) {
+            // Resume where the reader left off - or at the beginning for a fresh book
+            let resume_page_index = new_state.current_reading_position().map_or(0, |position| position.page_index);
+            if let Err(error) = self.load_page(resume_page_index) {
+                error!("GlobalStore::process_result - loading resumed page failed - {error}")
+            }
+        }
+        let unindexed_book_uuids = self.unindexed_books(&state.books, &new_state.books);
         *state = new_state;
         for listener in self.listeners.lock().unwrap().values() {
             listener.new_state(state.clone())
         }
+        drop(state);
+        self.index_books(unindexed_book_uuids);
+        if annotations_changed {
+            self.send_to_worker(GlobalResult::AnnotationsChanged);
+        }
+    }
+
+    // Saves the annotations changed since they were last saved, on the worker thread and without holding the state
+    // while writing
+    fn save_annotations(&self) {
+        let unsaved_annotations = std::mem::take(&mut *self.unsaved_annotations.lock().unwrap());
+        let (reading_positions, bookmarks, highlights) = {
+            let state = self.state.lock().unwrap();
+            (
+                Some(state.reading_positions.clone()).filter(|_| unsaved_annotations.reading_positions),
+                Some(state.bookmarks.clone()).filter(|_| unsaved_annotations.bookmarks),
+                Some(state.highlights.clone()).filter(|_| unsaved_annotations.highlights),
+            )
+        };
+        if let Some(reading_positions) = reading_positions {
+            if let Err(error) = self.library_storage.save_reading_positions(&reading_positions) {
+                error!("GlobalStore::save_annotations - saving reading positions failed - {error}")
+            }
+        }
+        if let Some(bookmarks) = bookmarks {
+            if let Err(error) = self.library_storage.save_bookmarks(&bookmarks) {
+                error!("GlobalStore::save_annotations - saving bookmarks failed - {error}")
+            }
+        }
+        if let Some(highlights) = highlights {
+            if let Err(error) = self.library_storage.save_highlights(&highlights) {
+                error!("GlobalStore::save_annotations - saving highlights failed - {error}")
+            }
+        }
+    }
+
+    fn dispatch_side_effect(&self, side_effect: GlobalSideEffect) {
+        for listener in self.listeners.lock().unwrap().values() {
+            listener.new_side_effect(side_effect.clone());
+        }
+    }
+
+    // The books that just became valid and aren't in the index yet
+    fn unindexed_books(&self, old_books: &[Book], new_books: &[Book]) -> Vec<String> {
+        let library_index = self.library_index.lock().unwrap();
+        // Books are indexed once the index is loaded and it's known which ones it lacks
+        let Some(library_index) = library_index.as_ref() else {
+            return vec![];
+        };
+        let is_valid = |book: &Book| matches!(book.loading_state, PdfLoadingState::ValidPdf { .. });
+        new_books
+            .iter()
+            .filter(|book| is_valid(book))
+            .filter(|book| !old_books.iter().any(|old_book| old_book.uuid == book.uuid && is_valid(old_book)))
+            .filter(|book| !library_index.contains_book(&book.uuid))
+            .map(|book| book.uuid.clone())
+            .collect()
+    }
+
+    // Called without the state held, the password vault is the host's and may take its time or call back in
+    fn index_books(&self, uuids: Vec<String>) {
+        for uuid in uuids {
+            let path = self.library_storage.source_path(&uuid);
+            let password = self.password(&uuid);
+            if let Err(error) = self.send_pdfium_action(PdfiumAction::IndexBook { uuid, path, password }) {
+                error!("GlobalStore::index_books - queueing indexing failed - {error}")
+            }
+        }
+    }
+
+    fn update_library_index(&self, uuid: String, page_texts: Vec<String>) {
+        // The book might have been deleted while its text was being extracted
+        if !self.state.lock().unwrap().books.iter().any(|book| book.uuid == uuid) {
+            return;
+        }
+        if let Err(error) = self.library_storage.save_book_index(&uuid, &page_texts) {
+            error!("GlobalStore::update_library_index - saving library index failed - {error}")
+        }
+        if let Some(library_index) = self.library_index.lock().unwrap().as_mut() {
+            library_index.add_book(uuid, page_texts);
+        }
+    }
+
+    fn persist_library(&self, books: &[Book]) {
+        let mut stored_books = self.stored_books.lock().unwrap();
+        match stored_books.as_deref().map(|previous_books| self.library_storage.save_books(books, previous_books)) {
+            Some(Ok(books)) => *stored_books = Some(books),
+            Some(Err(error)) => { error!("GlobalStore::persist_library - saving library failed - {error}") }
+            None => { error!("GlobalStore::persist_library - the library wasn't restored, not saving it") }
+        }
+        for book in books {
+            if let PdfLoadingState::ErrorPdf { error } = book.loading_state {
+                // Sources are kept while loading them might still work, after a retry or a restart
+                if !error.is_source_error() {
+                    continue;
+                }
+                if let Err(error) = self.library_storage.remove_source(&book.uuid) {
+                    error!("GlobalStore::persist_library - removing source failed - {error}")
+                }
+            }
+        }
     }
 
     fn reduce(state: GlobalState, action: GlobalResult) -> GlobalState {
         match action {
+            GlobalResult::LibraryRestored { books, reading_positions, bookmarks, highlights } => {
+                let mut new_state = state.clone();
+                for (uuid, reading_position) in reading_positions {
+                    new_state.reading_positions.entry(uuid).or_insert(reading_position);
+                }
+                for (uuid, book_bookmarks) in bookmarks {
+                    new_state.bookmarks.entry(uuid).or_insert(book_bookmarks);
+                }
+                for (uuid, book_highlights) in highlights {
+                    new_state.highlights.entry(uuid).or_insert(book_highlights);
+                }
+                let restored_books: Vec<Book> = books
+                    .into_iter()
+                    .filter(|book| !state.books.iter().any(|existing| existing.uuid == book.uuid))
+                    .collect();
+                new_state.books.splice(0..0, restored_books);
+                new_state
+            }
             GlobalResult::PdfLoading { uuid } => {
                 let mut new_state = state.clone();
                 new_state.books.push(
@@ -129,20 +647,39 @@ impl GlobalStore {
                         uuid,
                         thumbnail: None,
                         loading_state: PdfLoadingState::LoadingPdf,
+                        metadata_overlay: BookMetadataOverlay::default(),
                     }
                 );
                 new_state
             }
-            GlobalResult::PdfLoadingFailed { uuid } => {
+            GlobalResult::PdfLoadingFailed { uuid, error } => {
                 let mut new_state = state.clone();
                 for book in &mut new_state.books {
                     if uuid == book.uuid {
-                        book.loading_state = PdfLoadingState::ErrorPdf
+                        book.loading_state = PdfLoadingState::ErrorPdf { error }
                     }
                 }
                 new_state
             }
-            GlobalResult::PdfLoaded { id, title, author, thumbnail, page_count } => {
+            GlobalResult::PasswordRequired { uuid, file_name, wrong_password } => {
+                let mut new_state = state.clone();
+                Self::update_book(&mut new_state, &uuid, |book| {
+                    let file_name = file_name.clone().unwrap_or_else(|| book.display_title());
+                    book.loading_state = PdfLoadingState::PasswordRequired { file_name, wrong_password };
+                });
+                new_state
+            }
+            GlobalResult::EngineStatusChanged { status } => {
+                let mut new_state = state.clone();
+                new_state.engine_status = status;
+                new_state
+            }
+            GlobalResult::PdfReloading { uuid } => {
+                let mut new_state = state.clone();
+                Self::update_book(&mut new_state, &uuid, |book| book.loading_state = PdfLoadingState::LoadingPdf);
+                new_state
+            }
+            GlobalResult::PdfLoaded { id, title, author, thumbnail, page_count, outline } => {
                 let mut new_state = state.clone();
                 for book in &mut new_state.books {
                     if id == book.uuid {
@@ -155,63 +692,717 @@ impl GlobalStore {
                         };
                         new_state.current_book = Some(book.clone());
                         new_state.current_book_pages = (0..page_count)
-                            .map(|index| { Arc::new(Page { index, image: None }) })
+                            .map(|index| { Arc::new(Page { index, image: None, quality: RenderQuality::Preview, highlights: vec![], tiles: vec![] }) })
                             .collect();
+                        new_state.current_book_outline = outline.clone();
+                        new_state.current_search = None;
                     }
                 }
                 new_state
             }
+            GlobalResult::BookOpened { uuid, thumbnail, page_count, outline } => {
+                let mut new_state = state.clone();
+                for book in &mut new_state.books {
+                    if uuid == book.uuid {
+                        // Books restored from storage come without thumbnails - fill them in on first open
+                        if book.thumbnail.is_none() {
+                            book.thumbnail = thumbnail.clone();
+                            if let PdfLoadingState::ValidPdf { thumbnail: state_thumbnail, .. } = &mut book.loading_state {
+                                *state_thumbnail = thumbnail.clone();
+                            }
+                        }
+                        new_state.current_book = Some(book.clone());
+                        new_state.current_book_pages = (0..page_count)
+                            .map(|index| { Arc::new(Page { index, image: None, quality: RenderQuality::Preview, highlights: vec![], tiles: vec![] }) })
+                            .collect();
+                        new_state.current_book_outline = outline.clone();
+                        new_state.current_search = None;
+                    }
+                }
+                new_state
+            }
+            GlobalResult::BookDeleted { uuid } => {
+                let mut new_state = state.clone();
+                new_state.books.retain(|book| book.uuid != uuid);
+                if let Some(search) = &mut new_state.library_search {
+                    search.hits.retain(|hit| hit.book_uuid != uuid);
+                }
+                new_state.reading_positions.remove(&uuid);
+                new_state.bookmarks.remove(&uuid);
+                new_state.highlights.remove(&uuid);
+                if state.current_book.as_ref().map_or(false, |book| book.uuid == uuid) {
+                    new_state.current_book = None;
+                    new_state.current_book_pages = vec![];
+                    new_state.current_book_outline = vec![];
+                    new_state.current_search = None;
+                }
+                new_state
+            }
+            GlobalResult::BookRenamed { uuid, title } => {
+                let mut new_state = state.clone();
+                Self::update_book(&mut new_state, &uuid, |book| {
+                    book.metadata_overlay.title = Some(title.clone()).filter(|title| !title.is_empty());
+                });
+                new_state
+            }
+            GlobalResult::BookMetadataEdited { uuid, metadata_overlay } => {
+                let mut new_state = state.clone();
+                Self::update_book(&mut new_state, &uuid, |book| {
+                    book.metadata_overlay = BookMetadataOverlay {
+                        title: metadata_overlay.title.clone().filter(|title| !title.is_empty()),
+                        author: metadata_overlay.author.clone().filter(|author| !author.is_empty()),
+                    };
+                });
+                new_state
+            }
+            GlobalResult::SearchStarted { search_id, query } => {
+                let mut new_state = state.clone();
+                new_state.current_search = Some(SearchState { search_id, query, results: vec![], is_finished: false });
+                new_state
+            }
+            GlobalResult::SearchResultsFound { search_id, results } => {
+                let mut new_state = state.clone();
+                if let Some(search) = &mut new_state.current_search {
+                    if search.search_id == search_id {
+                        search.results.extend(results);
+                    }
+                }
+                new_state
+            }
+            GlobalResult::SearchFinished { search_id } => {
+                let mut new_state = state.clone();
+                if let Some(search) = &mut new_state.current_search {
+                    if search.search_id == search_id {
+                        search.is_finished = true;
+                    }
+                }
+                new_state
+            }
+            GlobalResult::SearchCancelled => {
+                let mut new_state = state.clone();
+                new_state.current_search = None;
+                new_state
+            }
+            GlobalResult::BookTextExtracted { .. } => state,
+            GlobalResult::AnnotationsChanged => state,
+            GlobalResult::FileExported { .. } => state,
+            GlobalResult::HighlightFailed { .. } | GlobalResult::RenderingFailed { .. } | GlobalResult::ExportFailed { .. } => state,
+            GlobalResult::LibrarySearchFinished { search } => {
+                let mut new_state = state.clone();
+                new_state.library_search = search;
+                new_state
+            }
             GlobalResult::PagesLoaded { pages } => {
                 let mut new_state = state.clone();
                 for page in pages {
                     let index = page.index as usize;
-                    new_state.current_book_pages[index] = page
+                    // Pages of a book that was closed in the meantime have nowhere to go
+                    if let Some(current_page) = new_state.current_book_pages.get_mut(index) {
+                        // A preview is only worth showing while there's no full render, even an outdated one
+                        if page.quality == RenderQuality::Full || current_page.image.is_none() {
+                            // Tiles are rendered separately, a new image of the page doesn't replace them
+                            *current_page = Arc::new(Page { tiles: current_page.tiles.clone(), ..(*page).clone() })
+                        }
+                    }
+                }
+                new_state
+            }
+            GlobalResult::PagesEvicted { page_indices } => {
+                let mut new_state = state.clone();
+                for page_index in page_indices {
+                    if let Some(page) = new_state.current_book_pages.get_mut(page_index as usize) {
+                        *page = Arc::new(Page { image: None, quality: RenderQuality::Preview, ..(**page).clone() });
+                    }
+                }
+                new_state
+            }
+            GlobalResult::TilesLoaded { page_index, tiles } => {
+                let mut new_state = state.clone();
+                if let Some(page) = new_state.current_book_pages.get_mut(page_index as usize) {
+                    *page = Arc::new(Page { tiles, ..(**page).clone() });
+                }
+                new_state
+            }
+            GlobalResult::ReadingPositionUpdated { page_index, scroll_offset } => {
+                let mut new_state = state.clone();
+                // Json has no NaN or infinity, a position stored with them couldn't be read back
+                if let Some(book) = state.current_book.as_ref().filter(|_| scroll_offset.is_finite()) {
+                    let last_page_index = max(state.current_book_pages.len() as i32 - 1, 0);
+                    new_state.reading_positions.insert(
+                        book.uuid.clone(),
+                        ReadingPosition {
+                            page_index: page_index.clamp(0, last_page_index),
+                            scroll_offset: scroll_offset.clamp(0.0, 1.0),
+                        },
+                    );
+                }
+                new_state
+            }
+            GlobalResult::ViewportChanged { viewport } => {
+                let mut new_state = state.clone();
+                new_state.viewport = Some(viewport);
+                new_state
+            }
+            GlobalResult::PageLayoutChanged { fit_mode, rotation } => {
+                let mut new_state = state.clone();
+                new_state.fit_mode = fit_mode;
+                new_state.page_rotation = rotation;
+                new_state
+            }
+            GlobalResult::BookmarksAdded { bookmarks } => {
+                let mut new_state = state.clone();
+                if let Some(book) = &state.current_book {
+                    let page_count = state.current_book_pages.len() as i32;
+                    // Imported notes may come from a different edition of the book
+                    let bookmarks = bookmarks
+                        .into_iter()
+                        .filter(|bookmark| (0..page_count).contains(&bookmark.page_index));
+                    merge_bookmarks(new_state.bookmarks.entry(book.uuid.clone()).or_default(), bookmarks);
+                }
+                new_state
+            }
+            GlobalResult::BookmarkRemoved { page_index } => {
+                let mut new_state = state.clone();
+                if let Some(book) = &state.current_book {
+                    if let Some(bookmarks) = new_state.bookmarks.get_mut(&book.uuid) {
+                        bookmarks.retain(|bookmark| bookmark.page_index != page_index);
+                        if bookmarks.is_empty() {
+                            new_state.bookmarks.remove(&book.uuid);
+                        }
+                    }
+                }
+                new_state
+            }
+            GlobalResult::HighlightCreated { uuid, highlight } => {
+                let mut new_state = state.clone();
+                // The book might have been deleted while the selection was being resolved
+                if state.books.iter().any(|book| book.uuid == uuid) {
+                    new_state.highlights.entry(uuid).or_default().push(highlight);
+                }
+                new_state
+            }
+            GlobalResult::HighlightRemoved { highlight_id } => {
+                let mut new_state = state.clone();
+                for book_highlights in new_state.highlights.values_mut() {
+                    book_highlights.retain(|highlight| highlight.highlight_id != highlight_id);
+                }
+                new_state.highlights.retain(|_, book_highlights| !book_highlights.is_empty());
+                new_state
+            }
+            GlobalResult::HighlightNoteEdited { highlight_id, note } => {
+                let mut new_state = state.clone();
+                for highlight in new_state.highlights.values_mut().flatten() {
+                    if highlight.highlight_id == highlight_id {
+                        highlight.note = note.clone();
+                    }
                 }
                 new_state
             }
         }
     }
 
+    // Pages carry their highlights so the ui can overlay them right on top of the page image
+    fn attach_highlights(state: &mut GlobalState) {
+        let GlobalState { current_book, current_book_pages, highlights, .. } = state;
+        let book_highlights = current_book.as_ref().and_then(|book| highlights.get(&book.uuid));
+        for page in current_book_pages {
+            let page_highlights: Vec<Highlight> = book_highlights
+                .map(|book_highlights| {
+                    book_highlights
+                        .iter()
+                        .filter(|highlight| highlight.page_index == page.index)
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default();
+            if page.highlights != page_highlights {
+                *page = Arc::new(Page { highlights: page_highlights, ..(**page).clone() });
+            }
+        }
+    }
+
+    fn update_book(state: &mut GlobalState, uuid: &str, update: impl Fn(&mut Book)) {
+        for book in &mut state.books {
+            if book.uuid == uuid {
+                update(book);
+            }
+        }
+        if let Some(book) = &mut state.current_book {
+            if book.uuid == uuid {
+                update(book);
+            }
+        }
+    }
 
     // This really shouldn't be here. I should find a way to do this on a main thread for each platform
-    fn init_worker_thread(store: Arc<GlobalStore>) -> WorkerThreadManager {
-        let (action_sender, action_receiver): (Sender<GlobalResult>, Receiver<GlobalResult>) = channel();
+    fn init_worker_thread(
+        store: Arc<GlobalStore>,
+        global_action_sender: Arc<Mutex<Sender<GlobalResult>>>,
+        action_receiver: Receiver<GlobalResult>,
+    ) -> WorkerThreadManager {
         let handle = thread::spawn(move || {
+            store.restore_library_index();
+            // When the unsaved annotations are due, the changes coming in until then are saved along
+            let mut annotations_save_time: Option<Instant> = None;
             loop {
-                let action = action_receiver.recv().unwrap();
-                store.clone().process_result(action);
+                let action = match annotations_save_time {
+                    Some(save_time) => action_receiver.recv_timeout(save_time.saturating_duration_since(Instant::now())),
+                    None => action_receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match action {
+                    Ok(GlobalResult::AnnotationsChanged) => {
+                        annotations_save_time.get_or_insert_with(|| Instant::now() + ANNOTATIONS_SAVE_DELAY);
+                    }
+                    Ok(action) => store.clone().process_result(action),
+                    Err(RecvTimeoutError::Timeout) => {
+                        annotations_save_time = None;
+                        store.save_annotations();
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        store.save_annotations();
+                        break;
+                    }
+                }
             }
         });
         WorkerThreadManager {
-            global_action_sender: Arc::new(Mutex::new(action_sender)),
+            global_action_sender,
             worker_thread_handle: handle,
         }
     }
 
-    fn load_pdf(&self, uuid: String, file_name: String, bytes: Vec<u8>) -> Result<()> {
+    fn is_current_book(&self, uuid: &str) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .current_book
+            .as_ref()
+            .map_or(false, |book| book.uuid == uuid)
+    }
+
+    fn send_to_worker(&self, action: GlobalResult) {
+        let worker_thread_manager = self.worker_thread_manager.lock().unwrap();
+        let Some(worker_thread_manager) = worker_thread_manager.as_ref() else {
+            return;
+        };
+        if worker_thread_manager.global_action_sender.lock().unwrap().send(action).is_err() {
+            error!("GlobalStore::send_to_worker - the worker thread is gone")
+        }
+    }
+
+    fn send_pdfium_action(&self, action: PdfiumAction) -> Result<()> {
+        if let EngineStatus::Unavailable { reason } = &*self.engine_status.lock().unwrap() {
+            return Err(anyhow::Error::new(EngineUnavailable).context(reason.clone()));
+        }
         let guard = self.pdfium_manager.lock().unwrap();
-        let pdfium_manager = guard.as_ref().context("No Pdfium Manager")?;
+        let pdfium_manager = guard.as_ref().context(EngineUnavailable)?;
         let pdfium_action_sender = pdfium_manager.pdfium_action_sender.lock().unwrap();
-        pdfium_action_sender.send(PdfiumAction::LoadPdf { uuid, file_name, bytes })?;
+        pdfium_action_sender.send(action).map_err(|_| EngineUnavailable)?;
         Ok(())
     }
 
-    fn load_page(&self, page_index: i32) -> Result<()> {
-        self.pdfium_manager
-            .lock()
-            .unwrap()
-            .as_ref()
-            .unwrap()
-            .pdfium_action_sender
-            .lock()
-            .unwrap()
-            .send(PdfiumAction::PageLoadRequested { page_index })
-            .unwrap();
+    // Pdfs are always loaded from their copy in the library, so pdfium reads them lazily and nothing
+    // outlives the open document
+    fn load_pdf(&self, uuid: &str, file_name: String, bytes: Vec<u8>) -> Result<()> {
+        self.library_storage.save_source(uuid, &bytes)?;
+        self.send_pdfium_action(PdfiumAction::LoadPdf { uuid: uuid.to_string(), file_name, path: self.library_storage.source_path(uuid), password: None })
+    }
+
+    fn load_pdf_from_path(&self, uuid: &str, file_name: String, path: String) -> Result<()> {
+        let mut source = File::open(&path).context(format!("Opening {path}"))?;
+        self.library_storage.copy_source(uuid, &mut source)?;
+        self.send_pdfium_action(PdfiumAction::LoadPdf { uuid: uuid.to_string(), file_name, path: self.library_storage.source_path(uuid), password: None })
+    }
+
+    #[cfg(unix)]
+    fn load_pdf_from_fd(&self, uuid: &str, file_name: String, fd: i32) -> Result<()> {
+        use std::os::unix::io::FromRawFd;
+        if fd < 0 {
+            bail!("Invalid file descriptor {fd}")
+        }
+        // Safety: the host hands the descriptor over, nothing else closes it
+        let mut source = unsafe { File::from_raw_fd(fd) };
+        self.library_storage.copy_source(uuid, &mut source)?;
+        self.send_pdfium_action(PdfiumAction::LoadPdf { uuid: uuid.to_string(), file_name, path: self.library_storage.source_path(uuid), password: None })
+    }
+
+    // The descriptor is still closed, the store owns it either way
+    #[cfg(not(unix))]
+    fn load_pdf_from_fd(&self, _uuid: &str, _file_name: String, fd: i32) -> Result<()> {
+        #[cfg(windows)]
+        if fd >= 0 {
+            extern "C" {
+                fn _close(fd: std::os::raw::c_int) -> std::os::raw::c_int;
+            }
+            // Safety: the host hands the descriptor over, nothing else closes it
+            unsafe { _close(fd) };
+        }
+        bail!("Loading from file descriptor {fd} is only supported on unix")
+    }
+
+    fn open_book(self: Arc<Self>, uuid: String) -> Result<()> {
+        if self.is_current_book(&uuid) {
+            return Ok(());
+        }
+        // A book that failed to load keeps its error, a retryable one would otherwise be retried on every open
+        {
+            let state = self.state.lock().unwrap();
+            let book = state.books.iter().find(|book| book.uuid == uuid).context(format!("No book {uuid}"))?;
+            if !matches!(book.loading_state, PdfLoadingState::ValidPdf { .. }) {
+                bail!("Book {uuid} can't be opened");
+            }
+        }
+        let path = self.library_storage.source_path(&uuid);
+        let password = self.password(&uuid);
+        if let Err(error) = self.send_pdfium_action(PdfiumAction::OpenPdf { uuid: uuid.clone(), path, password }) {
+            self.process_result(GlobalResult::PdfLoadingFailed { uuid, error: loading_error(&error) });
+            return Err(error);
+        }
         Ok(())
     }
+
+    fn submit_password(self: Arc<Self>, uuid: String, password: String) -> Result<()> {
+        let file_name = {
+            let state = self.state.lock().unwrap();
+            let book = state.books.iter().find(|book| book.uuid == uuid).context(format!("No book {uuid}"))?;
+            match &book.loading_state {
+                PdfLoadingState::PasswordRequired { file_name, .. } => file_name.clone(),
+                _ => bail!("Book {uuid} doesn't need a password"),
+            }
+        };
+        self.passwords.lock().unwrap().insert(uuid.clone(), password.clone());
+        if let Some(vault) = self.password_vault.lock().unwrap().as_ref() {
+            vault.save_password(uuid.clone(), password.clone());
+        }
+        self.reload_pdf(uuid, file_name, Some(password));
+        Ok(())
+    }
+
+    fn retry_loading(self: Arc<Self>, uuid: String) -> Result<()> {
+        let file_name = {
+            let state = self.state.lock().unwrap();
+            let book = state.books.iter().find(|book| book.uuid == uuid).context(format!("No book {uuid}"))?;
+            match &book.loading_state {
+                PdfLoadingState::ErrorPdf { error } if error.is_retryable() => book.display_title(),
+                _ => bail!("Book {uuid} can't be retried"),
+            }
+        };
+        let password = self.password(&uuid);
+        self.reload_pdf(uuid, file_name, password);
+        Ok(())
+    }
+
+    // Loads a book of the library again from its source
+    fn reload_pdf(self: Arc<Self>, uuid: String, file_name: String, password: Option<String>) {
+        self.clone().process_result(GlobalResult::PdfReloading { uuid: uuid.clone() });
+        let path = self.library_storage.source_path(&uuid);
+        if let Err(error) = self.send_pdfium_action(PdfiumAction::LoadPdf { uuid: uuid.clone(), file_name, path, password }) {
+            error!("GlobalStore::reload_pdf - loading {uuid} failed - {error}");
+            self.process_result(GlobalResult::PdfLoadingFailed { uuid, error: loading_error(&error) })
+        }
+    }
+
+    fn password(&self, uuid: &str) -> Option<String> {
+        let mut passwords = self.passwords.lock().unwrap();
+        if let Some(password) = passwords.get(uuid) {
+            return Some(password.clone());
+        }
+        let password = self.password_vault.lock().unwrap().as_ref()?.load_password(uuid.to_string())?;
+        passwords.insert(uuid.to_string(), password.clone());
+        Some(password)
+    }
+
+    fn forget_password(&self, uuid: &str) {
+        self.passwords.lock().unwrap().remove(uuid);
+        if let Some(vault) = self.password_vault.lock().unwrap().as_ref() {
+            vault.remove_password(uuid.to_string());
+        }
+    }
+
+    fn export_annotated_pdf(&self, uuid: String) -> Result<()> {
+        let (file_name, highlights, bookmarks) = {
+            let state = self.state.lock().unwrap();
+            let book = state.books.iter().find(|book| book.uuid == uuid).context(format!("No book {uuid}"))?;
+            (
+                format!("{}.pdf", book.display_title()),
+                state.highlights.get(&uuid).cloned().unwrap_or_default(),
+                state.bookmarks.get(&uuid).cloned().unwrap_or_default(),
+            )
+        };
+        let path = self.library_storage.source_path(&uuid);
+        let password = self.password(&uuid);
+        self.send_pdfium_action(PdfiumAction::ExportAnnotatedPdf { uuid, file_name, path, password, highlights, bookmarks })
+    }
+
+    fn export_notes(&self, uuid: String, format: NotesFormat) -> Result<()> {
+        let document = {
+            let state = self.state.lock().unwrap();
+            let book = state.books.iter().find(|book| book.uuid == uuid).context(format!("No book {uuid}"))?;
+            export_notes(
+                book,
+                state.bookmarks.get(&uuid).map_or(&[], Vec::as_slice),
+                state.highlights.get(&uuid).map_or(&[], Vec::as_slice),
+                format,
+            )?
+        };
+        self.dispatch_side_effect(GlobalSideEffect::NotesExported { uuid, format, document });
+        Ok(())
+    }
+
+    fn delete_book(self: Arc<Self>, uuid: String) -> Result<()> {
+        let is_open = self.is_current_book(&uuid);
+        self.clone().process_result(GlobalResult::BookDeleted { uuid: uuid.clone() });
+        self.forget_password(&uuid);
+        if is_open {
+            self.send_pdfium_action(PdfiumAction::ClosePdf)?;
+        }
+        if let Some(library_index) = self.library_index.lock().unwrap().as_mut() {
+            library_index.remove_book(&uuid);
+        }
+        self.library_storage.remove_book_index(&uuid)?;
+        remove_document_renders(&self.library_storage.render_cache_directory(), &uuid)?;
+        self.library_storage.remove_source(&uuid)
+    }
+
+    fn search_library(self: Arc<Self>, query: String) {
+        let search = if query.trim().is_empty() {
+            None
+        } else {
+            let hits = self.library_index.lock().unwrap().as_ref().map_or_else(Vec::new, |library_index| library_index.search(&query));
+            Some(LibrarySearchState { query, hits })
+        };
+        self.process_result(GlobalResult::LibrarySearchFinished { search })
+    }
+
+    fn search(self: Arc<Self>, query: String) -> Result<()> {
+        if query.trim().is_empty() {
+            return self.cancel_search();
+        }
+        let search_id = Uuid::new_v4().to_string();
+        self.clone().process_result(GlobalResult::SearchStarted { search_id: search_id.clone(), query: query.clone() });
+        if let Err(error) = self.send_pdfium_action(PdfiumAction::Search { search_id: search_id.clone(), query }) {
+            // No results are coming, so the search doesn't stay in progress
+            self.process_result(GlobalResult::SearchFinished { search_id });
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    fn cancel_search(self: Arc<Self>) -> Result<()> {
+        self.clone().process_result(GlobalResult::SearchCancelled);
+        self.send_pdfium_action(PdfiumAction::CancelSearch)
+    }
+
+    fn add_highlight(&self, page_index: i32, selection: PageSelection, color: u32, note: String) -> Result<()> {
+        let Some(uuid) = self.state.lock().unwrap().current_book.as_ref().map(|book| book.uuid.clone()) else {
+            bail!("No open book")
+        };
+        if selection.view_width <= 0.0 || selection.view_height <= 0.0 {
+            bail!("Empty view size {}x{}", selection.view_width, selection.view_height)
+        }
+        let highlight_id = Uuid::new_v4().to_string();
+        self.send_pdfium_action(PdfiumAction::CreateHighlight { uuid, highlight_id, page_index, selection, color, note })
+    }
+
+    fn set_viewport(self: Arc<Self>, viewport: Viewport) -> Result<()> {
+        let is_valid = |value: f32| value.is_finite() && value > 0.0;
+        if ![viewport.width, viewport.height, viewport.zoom, viewport.device_scale].into_iter().all(is_valid) {
+            bail!("Invalid viewport {viewport:?}")
+        }
+        self.update_page_layout(GlobalResult::ViewportChanged { viewport })
+    }
+
+    // Applies a result affecting the page layout and re-renders the pages around the reading position if the layout changed
+    fn update_page_layout(self: Arc<Self>, result: GlobalResult) -> Result<()> {
+        let old_page_layout = Self::page_layout(&self.state.lock().unwrap());
+        self.clone().process_result(result);
+        let (page_layout, page_index) = {
+            let state = self.state.lock().unwrap();
+            (Self::page_layout(&state), state.current_reading_position().map_or(0, |position| position.page_index))
+        };
+        if page_layout != old_page_layout {
+            self.send_pdfium_action(PdfiumAction::SetPageLayout { page_layout, page_index })?;
+        }
+        Ok(())
+    }
+
+    fn page_layout(state: &GlobalState) -> PageLayout {
+        let (box_width, box_height) = match &state.viewport {
+            Some(viewport) => (viewport.pixel_width().ceil() as i32, viewport.pixel_height().ceil() as i32),
+            None => (DEFAULT_PAGE_LAYOUT.box_width, DEFAULT_PAGE_LAYOUT.box_height),
+        };
+        PageLayout { box_width, box_height, fit_mode: state.fit_mode, rotation: state.page_rotation }
+    }
+
+    fn load_tiles(&self, page_index: i32, zoom_level: i32, region: PageRect) -> Result<()> {
+        let zoom_level = zoom_level.clamp(0, MAX_TILE_ZOOM_LEVEL);
+        self.send_pdfium_action(PdfiumAction::LoadTiles { page_index, zoom_level, region })
+    }
+
+    fn go_to_page(self: Arc<Self>, page_index: i32) -> Result<()> {
+        self.clone().process_result(GlobalResult::ReadingPositionUpdated { page_index, scroll_offset: 0.0 });
+        self.load_page(page_index)
+    }
+
+    fn load_page(&self, page_index: i32) -> Result<()> {
+        self.send_pdfium_action(PdfiumAction::PageLoadRequested { page_index })
+    }
 }
 
 struct WorkerThreadManager {
     global_action_sender: Arc<Mutex<Sender<GlobalResult>>>,
+    #[allow(dead_code)]
     worker_thread_handle: JoinHandle<()>,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::*;
+    use crate::test_support::TemporaryDirectory;
+
+    fn valid_book(uuid: &str) -> Book {
+        Book {
+            uuid: uuid.to_string(),
+            thumbnail: None,
+            loading_state: PdfLoadingState::ValidPdf { title: "Title".to_string(), author: "Author".to_string(), thumbnail: None, page_count: 3 },
+            metadata_overlay: BookMetadataOverlay::default(),
+        }
+    }
+
+    fn store(directory: &TemporaryDirectory) -> Arc<GlobalStore> {
+        Arc::new(GlobalStore::new(directory.path().to_str().unwrap().to_string()))
+    }
+
+    #[test]
+    fn reading_positions_only_take_finite_scroll_offsets() {
+        let directory = TemporaryDirectory::new();
+        let mut state = store(&directory).state.lock().unwrap().clone();
+        state.current_book = Some(valid_book("book"));
+        for scroll_offset in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            let new_state = GlobalStore::reduce(state.clone(), GlobalResult::ReadingPositionUpdated { page_index: 0, scroll_offset });
+            assert!(new_state.reading_positions.is_empty());
+        }
+        let new_state = GlobalStore::reduce(state, GlobalResult::ReadingPositionUpdated { page_index: 0, scroll_offset: 1.5 });
+        assert_eq!(new_state.reading_positions["book"], ReadingPosition { page_index: 0, scroll_offset: 1.0 });
+    }
+
+    #[test]
+    fn only_valid_books_are_opened() {
+        let directory = TemporaryDirectory::new();
+        let store = store(&directory);
+        let mut corrupt_book = valid_book("corrupt");
+        corrupt_book.loading_state = PdfLoadingState::ErrorPdf { error: PdfLoadingError::CorruptFile };
+        store.state.lock().unwrap().books = vec![valid_book("valid"), corrupt_book.clone()];
+        store.clone().dispatch_action(GlobalAction::OpenBook { uuid: "corrupt".to_string() });
+        store.clone().dispatch_action(GlobalAction::OpenBook { uuid: "valid".to_string() });
+        let books = store.state.lock().unwrap().books.clone();
+        assert!(books[1] == corrupt_book);
+        // Pdfium was never started
+        assert!(books[0].loading_state == PdfLoadingState::ErrorPdf { error: PdfLoadingError::EngineUnavailable });
+    }
+
+    #[test]
+    fn annotations_are_saved_apart_from_the_changes() {
+        let directory = TemporaryDirectory::new();
+        let store = store(&directory);
+        store.state.lock().unwrap().books = vec![valid_book("book")];
+        store.clone().process_result(GlobalResult::BookOpened {
+            uuid: "book".to_string(),
+            thumbnail: None,
+            page_count: 3,
+            outline: vec![],
+        });
+        store.clone().process_result(GlobalResult::ReadingPositionUpdated { page_index: 1, scroll_offset: 0.5 });
+        store.clone().process_result(GlobalResult::ReadingPositionUpdated { page_index: 2, scroll_offset: 0.25 });
+        let storage = LibraryStorage::new(directory.path());
+        assert!(storage.load_reading_positions().unwrap().is_empty());
+        store.save_annotations();
+        assert_eq!(
+            storage.load_reading_positions().unwrap(),
+            HashMap::from([("book".to_string(), ReadingPosition { page_index: 2, scroll_offset: 0.25 })]),
+        );
+        assert!(!directory.path().join("bookmarks.json").exists());
+    }
+
+    #[test]
+    fn deleting_a_book_removes_its_renders() {
+        let directory = TemporaryDirectory::new();
+        let store = store(&directory);
+        store.state.lock().unwrap().books = vec![valid_book("deleted"), valid_book("kept")];
+        let render_cache_directory = store.library_storage.render_cache_directory();
+        for document_directory in ["deleted_10_1", "deleted_20_2", "kept_10_1"] {
+            fs::create_dir_all(render_cache_directory.join(document_directory)).unwrap();
+            fs::write(render_cache_directory.join(document_directory).join("0_10x10_0.png"), b"png").unwrap();
+        }
+        store.clone().dispatch_action(GlobalAction::DeleteBook { uuid: "deleted".to_string() });
+        let document_directories: Vec<String> = fs::read_dir(&render_cache_directory)
+            .unwrap()
+            .map(|document_directory| document_directory.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(document_directories, vec!["kept_10_1"]);
+    }
+
+    #[test]
+    fn a_file_the_host_couldnt_open_is_not_retried() {
+        let directory = TemporaryDirectory::new();
+        let store = store(&directory);
+        store.clone().dispatch_action(GlobalAction::MarkPdfLoading { uuid: "book".to_string() });
+        store.clone().dispatch_action(GlobalAction::MarkPdfLoadingFailed { uuid: "book".to_string() });
+        assert!(store.clone().retry_loading("book".to_string()).is_err());
+        let books = store.state.lock().unwrap().books.clone();
+        assert!(books[0].loading_state == PdfLoadingState::ErrorPdf { error: PdfLoadingError::SourceUnavailable });
+    }
+
+    #[test]
+    fn a_broken_file_leaves_the_rest_of_the_library_restored() {
+        let directory = TemporaryDirectory::new();
+        let storage = LibraryStorage::new(directory.path());
+        storage.save_books(&[valid_book("book")], &[]).unwrap();
+        storage.save_bookmarks(&HashMap::from([("book".to_string(), vec![Bookmark { page_index: 1, note: String::new() }])])).unwrap();
+        fs::write(directory.path().join("reading_positions.json"), r#"{"book":{"page_index":1,"scroll_offset":null}}"#).unwrap();
+        let GlobalResult::LibraryRestored { books, reading_positions, bookmarks, highlights } = store(&directory).restore_library() else {
+            panic!("The library wasn't restored")
+        };
+        assert!(books == vec![valid_book("book")]);
+        assert!(reading_positions.is_empty());
+        assert_eq!(bookmarks.len(), 1);
+        assert!(highlights.is_empty());
+    }
+
+    #[test]
+    fn a_library_that_failed_to_load_is_not_overwritten() {
+        let directory = TemporaryDirectory::new();
+        let library_path = directory.path().join("library.json");
+        fs::write(&library_path, "[{").unwrap();
+        let store = store(&directory);
+        store.clone().process_result(store.restore_library());
+        store.clone().process_result(GlobalResult::PdfLoading { uuid: "new".to_string() });
+        assert_eq!(store.state.lock().unwrap().books.len(), 1);
+        assert_eq!(fs::read_to_string(&library_path).unwrap(), "[{");
+    }
+
+    #[test]
+    fn a_restored_library_is_saved() {
+        let directory = TemporaryDirectory::new();
+        LibraryStorage::new(directory.path()).save_books(&[valid_book("restored")], &[]).unwrap();
+        let store = store(&directory);
+        store.clone().process_result(store.restore_library());
+        store.clone().process_result(GlobalResult::PdfLoading { uuid: "new".to_string() });
+        store.clone().process_result(GlobalResult::PdfLoaded {
+            id: "new".to_string(),
+            title: "Title".to_string(),
+            author: "Author".to_string(),
+            thumbnail: None,
+            page_count: 3,
+            outline: vec![],
+        });
+        let stored_uuids: Vec<String> = LibraryStorage::new(directory.path())
+            .load_books()
+            .unwrap()
+            .into_iter()
+            .map(|stored_book| stored_book.uuid)
+            .collect();
+        assert_eq!(stored_uuids, vec!["restored", "new"]);
+    }
+}
@@ -7,4 +7,18 @@ pub mod books_state;
 pub mod pages_state;
 mod pdfium_manager;
 mod domain;
+mod bookmarks;
+mod library_index;
+mod library_storage;
+mod pdf_text;
+mod bitmap_encoding;
+mod page_cache;
+mod render_disk_cache;
+mod render_scheduler;
+mod page_layout;
+mod page_tiles;
+mod pdf_annotations;
+mod notes_export;
 
+#[cfg(test)]
+mod test_support;
@@ -0,0 +1,208 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use crate::domain::LibrarySearchHit;
+
+const MAX_LIBRARY_SEARCH_HITS: usize = 100;
+const SNIPPET_CONTEXT_CHARS: usize = 40;
+
+struct Posting {
+    book_uuid: String,
+    page_index: i32,
+    term_frequency: u32,
+}
+
+// Inverted index from lowercase words to the book pages containing them.
+// Only the page texts are persisted, postings are rebuilt from them on load.
+#[derive(Default)]
+pub struct LibraryIndex {
+    page_texts: HashMap<String, Vec<String>>,
+    postings: HashMap<String, Vec<Posting>>,
+    indexed_pages_count: usize,
+}
+
+impl LibraryIndex {
+    pub fn from_page_texts(page_texts: HashMap<String, Vec<String>>) -> LibraryIndex {
+        let mut index = LibraryIndex::default();
+        for (book_uuid, book_page_texts) in page_texts {
+            index.add_book(book_uuid, book_page_texts);
+        }
+        index
+    }
+
+    pub fn book_uuids(&self) -> impl Iterator<Item=&String> {
+        self.page_texts.keys()
+    }
+
+    pub fn contains_book(&self, book_uuid: &str) -> bool {
+        self.page_texts.contains_key(book_uuid)
+    }
+
+    pub fn add_book(&mut self, book_uuid: String, page_texts: Vec<String>) {
+        self.remove_book(&book_uuid);
+        for (page_index, page_text) in page_texts.iter().enumerate() {
+            let mut term_frequencies: HashMap<String, u32> = HashMap::new();
+            for term in tokenize(page_text) {
+                *term_frequencies.entry(term).or_insert(0) += 1;
+            }
+            for (term, term_frequency) in term_frequencies {
+                self.postings.entry(term).or_default().push(Posting {
+                    book_uuid: book_uuid.clone(),
+                    page_index: page_index as i32,
+                    term_frequency,
+                });
+            }
+        }
+        self.indexed_pages_count += page_texts.len();
+        self.page_texts.insert(book_uuid, page_texts);
+    }
+
+    pub fn remove_book(&mut self, book_uuid: &str) {
+        let Some(page_texts) = self.page_texts.remove(book_uuid) else {
+            return;
+        };
+        self.indexed_pages_count -= page_texts.len();
+        for postings in self.postings.values_mut() {
+            postings.retain(|posting| posting.book_uuid != book_uuid);
+        }
+        self.postings.retain(|_, postings| !postings.is_empty());
+    }
+
+    // Ranks pages by the tf-idf sum of the query terms they contain
+    pub fn search(&self, query: &str) -> Vec<LibrarySearchHit> {
+        let terms: Vec<String> = tokenize(query).collect();
+        let mut scores: HashMap<(&str, i32), f32> = HashMap::new();
+        for term in &terms {
+            let Some(postings) = self.postings.get(term) else {
+                continue;
+            };
+            let inverse_document_frequency = (self.indexed_pages_count as f32 / postings.len() as f32).ln() + 1.0;
+            for posting in postings {
+                *scores.entry((posting.book_uuid.as_str(), posting.page_index)).or_insert(0.0) +=
+                    posting.term_frequency as f32 * inverse_document_frequency;
+            }
+        }
+        let mut hits: Vec<LibrarySearchHit> = scores
+            .into_iter()
+            .map(|((book_uuid, page_index), score)| LibrarySearchHit {
+                book_uuid: book_uuid.to_string(),
+                page_index,
+                snippet: self.snippet(book_uuid, page_index, &terms),
+                score,
+            })
+            .collect();
+        hits.sort_by(|first, second| {
+            second.score
+                .partial_cmp(&first.score)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| first.book_uuid.cmp(&second.book_uuid))
+                .then_with(|| first.page_index.cmp(&second.page_index))
+        });
+        hits.truncate(MAX_LIBRARY_SEARCH_HITS);
+        hits
+    }
+
+    fn snippet(&self, book_uuid: &str, page_index: i32, terms: &[String]) -> String {
+        let Some(page_text) = self.page_texts.get(book_uuid).and_then(|texts| texts.get(page_index as usize)) else {
+            return String::new();
+        };
+        let chars: Vec<char> = page_text.chars().collect();
+        let lowercase_text: String = chars.iter().map(|character| fold_case(*character)).collect();
+        let match_start = terms
+            .iter()
+            .filter_map(|term| lowercase_text.find(term.as_str()))
+            .min()
+            .map_or(0, |byte_index| lowercase_text[..byte_index].chars().count());
+        let snippet_start = match_start.saturating_sub(SNIPPET_CONTEXT_CHARS);
+        let snippet_end = (match_start + SNIPPET_CONTEXT_CHARS).min(chars.len());
+        chars[snippet_start..snippet_end]
+            .iter()
+            .map(|character| if character.is_whitespace() { ' ' } else { *character })
+            .collect::<String>()
+            .trim()
+            .to_string()
+    }
+}
+
+fn tokenize(text: &str) -> impl Iterator<Item=String> + '_ {
+    text.split(|character: char| !character.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.chars().map(fold_case).collect())
+}
+
+fn fold_case(character: char) -> char {
+    character.to_lowercase().next().unwrap_or(character)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(books: &[(&str, &[&str])]) -> LibraryIndex {
+        LibraryIndex::from_page_texts(
+            books
+                .iter()
+                .map(|(uuid, page_texts)| (uuid.to_string(), page_texts.iter().map(|text| text.to_string()).collect()))
+                .collect(),
+        )
+    }
+
+    fn ranked_pages(hits: &[LibrarySearchHit]) -> Vec<(&str, i32)> {
+        hits.iter().map(|hit| (hit.book_uuid.as_str(), hit.page_index)).collect()
+    }
+
+    #[test]
+    fn pages_rank_by_how_often_they_mention_the_query() {
+        let library_index = index(&[
+            ("first", &["a whale", "whale after whale", "no mention"]),
+            ("second", &["whale whale whale"]),
+        ]);
+        let hits = library_index.search("Whale");
+        assert_eq!(ranked_pages(&hits), vec![("second", 0), ("first", 1), ("first", 0)]);
+    }
+
+    #[test]
+    fn rare_terms_outweigh_common_ones() {
+        let library_index = index(&[("book", &["the sea", "the the harpoon", "the sea", "the sea"])]);
+        let hits = library_index.search("the harpoon");
+        assert_eq!(hits[0].page_index, 1);
+        assert_eq!(hits.len(), 4);
+        assert!(hits[1..].iter().all(|hit| hit.score < hits[0].score));
+    }
+
+    #[test]
+    fn equal_scores_rank_by_book_then_page() {
+        let library_index = index(&[("second", &["ship"]), ("first", &["ship", "ship"])]);
+        let hits = library_index.search("ship");
+        assert_eq!(ranked_pages(&hits), vec![("first", 0), ("first", 1), ("second", 0)]);
+    }
+
+    #[test]
+    fn removed_books_are_not_found() {
+        let mut library_index = index(&[("first", &["whale"]), ("second", &["whale"])]);
+        library_index.remove_book("first");
+        assert!(!library_index.contains_book("first"));
+        assert_eq!(ranked_pages(&library_index.search("whale")), vec![("second", 0)]);
+        library_index.remove_book("second");
+        assert!(library_index.search("whale").is_empty());
+    }
+
+    #[test]
+    fn snippets_surround_the_match_in_chars() {
+        let filler = "ż".repeat(60);
+        let page_text = format!("{filler} Gęśla\njaźń {filler}");
+        let library_index = index(&[("book", &[page_text.as_str()])]);
+        let hits = library_index.search("GĘŚLA");
+        assert_eq!(hits.len(), 1);
+        let snippet = &hits[0].snippet;
+        assert!(snippet.contains("Gęśla jaźń"), "{snippet}");
+        assert_eq!(snippet.chars().count(), 2 * SNIPPET_CONTEXT_CHARS);
+        assert!(snippet.starts_with(&format!("{} Gęśla", "ż".repeat(SNIPPET_CONTEXT_CHARS - 1))), "{snippet}");
+    }
+
+    #[test]
+    fn snippets_start_with_the_page_when_the_match_is_near_it() {
+        let library_index = index(&[("book", &["Ähnlich wie ein Wal"])]);
+        let hits = library_index.search("ähnlich");
+        assert_eq!(hits[0].snippet, "Ähnlich wie ein Wal");
+    }
+}
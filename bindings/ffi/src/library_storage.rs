@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{ErrorKind, Read};
+use std::path::PathBuf;
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use crate::domain::{Book, Bookmark, BookMetadataOverlay, Highlight, PdfLoadingState, ReadingPosition};
+
+const LIBRARY_FILE_NAME: &str = "library.json";
+const READING_POSITIONS_FILE_NAME: &str = "reading_positions.json";
+const BOOKMARKS_FILE_NAME: &str = "bookmarks.json";
+const HIGHLIGHTS_FILE_NAME: &str = "highlights.json";
+const LIBRARY_INDEX_DIRECTORY_NAME: &str = "library_index";
+const SOURCES_DIRECTORY_NAME: &str = "sources";
+const RENDER_CACHE_DIRECTORY_NAME: &str = "render_cache";
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StoredBook {
+    pub uuid: String,
+    pub title: String,
+    pub author: String,
+    pub page_count: i32,
+    #[serde(default)]
+    pub metadata_overlay: BookMetadataOverlay,
+    // Added while encrypted and never opened, the title is then the file name
+    #[serde(default)]
+    pub password_required: bool,
+}
+
+impl StoredBook {
+    pub fn to_book(&self) -> Book {
+        let loading_state = if self.password_required {
+            PdfLoadingState::PasswordRequired { file_name: self.title.clone(), wrong_password: false }
+        } else {
+            PdfLoadingState::ValidPdf {
+                title: self.title.clone(),
+                author: self.author.clone(),
+                thumbnail: None,
+                page_count: self.page_count,
+            }
+        };
+        Book {
+            uuid: self.uuid.clone(),
+            thumbnail: None,
+            loading_state,
+            metadata_overlay: self.metadata_overlay.clone(),
+        }
+    }
+}
+
+// File backed library - a json index of all valid books plus a copy of every pdf source
+pub struct LibraryStorage {
+    root: PathBuf,
+}
+
+impl LibraryStorage {
+    pub fn new(root: impl Into<PathBuf>) -> LibraryStorage {
+        LibraryStorage { root: root.into() }
+    }
+
+    pub fn load_books(&self) -> Result<Vec<StoredBook>> {
+        let books = self.read_json(LIBRARY_FILE_NAME)?.unwrap_or_default();
+        Ok(books)
+    }
+
+    // previous_books are the ones stored last, the ones stored now are returned
+    pub fn save_books(&self, books: &[Book], previous_books: &[StoredBook]) -> Result<Vec<StoredBook>> {
+        // Books waiting for a password or a retry, or loading again, keep what was stored of them. One waiting for
+        // a password that was never opened is stored as such, so its source isn't left behind.
+        let stored_books: Vec<StoredBook> = books
+            .iter()
+            .filter_map(|book| {
+                let previous_book = || previous_books.iter().find(|stored_book| stored_book.uuid == book.uuid).cloned();
+                match book.loading_state {
+                    PdfLoadingState::ErrorPdf { error } if error.is_source_error() => None,
+                    PdfLoadingState::ValidPdf { .. } => Self::to_stored_book(book),
+                    PdfLoadingState::PasswordRequired { .. } => previous_book().or_else(|| Self::to_stored_book(book)),
+                    _ => previous_book(),
+                }
+            })
+            .collect();
+        self.write_json(LIBRARY_FILE_NAME, &stored_books)?;
+        Ok(stored_books)
+    }
+
+    pub fn load_reading_positions(&self) -> Result<HashMap<String, ReadingPosition>> {
+        let reading_positions = self.read_json(READING_POSITIONS_FILE_NAME)?.unwrap_or_default();
+        Ok(reading_positions)
+    }
+
+    pub fn save_reading_positions(&self, reading_positions: &HashMap<String, ReadingPosition>) -> Result<()> {
+        self.write_json(READING_POSITIONS_FILE_NAME, reading_positions)
+    }
+
+    pub fn load_bookmarks(&self) -> Result<HashMap<String, Vec<Bookmark>>> {
+        let bookmarks = self.read_json(BOOKMARKS_FILE_NAME)?.unwrap_or_default();
+        Ok(bookmarks)
+    }
+
+    pub fn save_bookmarks(&self, bookmarks: &HashMap<String, Vec<Bookmark>>) -> Result<()> {
+        self.write_json(BOOKMARKS_FILE_NAME, bookmarks)
+    }
+
+    pub fn load_highlights(&self) -> Result<HashMap<String, Vec<Highlight>>> {
+        let highlights = self.read_json(HIGHLIGHTS_FILE_NAME)?.unwrap_or_default();
+        Ok(highlights)
+    }
+
+    pub fn save_highlights(&self, highlights: &HashMap<String, Vec<Highlight>>) -> Result<()> {
+        self.write_json(HIGHLIGHTS_FILE_NAME, highlights)
+    }
+
+    // The page texts of every indexed book. Each book has a file of its own, so indexing or deleting
+    // a book doesn't rewrite the text of the whole library.
+    pub fn load_library_index(&self) -> Result<HashMap<String, Vec<String>>> {
+        let directory = self.root.join(LIBRARY_INDEX_DIRECTORY_NAME);
+        let entries = match fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(error) => return Err(error).context(format!("Listing {}", directory.display())),
+        };
+        let mut page_texts = HashMap::new();
+        for entry in entries {
+            let path = entry.context(format!("Listing {}", directory.display()))?.path();
+            if path.extension().map_or(true, |extension| extension != "json") {
+                continue;
+            }
+            let Some(uuid) = path.file_stem().and_then(|file_stem| file_stem.to_str()) else {
+                continue;
+            };
+            // A broken book index only gets the book indexed again
+            match self.read_json(&Self::book_index_file_name(uuid)) {
+                Ok(Some(book_page_texts)) => {
+                    page_texts.insert(uuid.to_string(), book_page_texts);
+                }
+                Ok(None) => {}
+                Err(error) => { error!("LibraryStorage::load_library_index - skipping index of {uuid} - {error}") }
+            }
+        }
+        Ok(page_texts)
+    }
+
+    pub fn save_book_index(&self, uuid: &str, page_texts: &[String]) -> Result<()> {
+        self.write_json(&Self::book_index_file_name(uuid), page_texts)
+    }
+
+    pub fn remove_book_index(&self, uuid: &str) -> Result<()> {
+        match fs::remove_file(self.root.join(Self::book_index_file_name(uuid))) {
+            Ok(_) => Ok(()),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(error).context(format!("Removing index of {uuid}")),
+        }
+    }
+
+    fn book_index_file_name(uuid: &str) -> String {
+        format!("{LIBRARY_INDEX_DIRECTORY_NAME}/{uuid}.json")
+    }
+
+    pub fn save_source(&self, uuid: &str, bytes: &[u8]) -> Result<()> {
+        fs::create_dir_all(self.sources_directory()).context("Creating sources directory")?;
+        fs::write(self.source_path(uuid), bytes).context(format!("Writing source of {uuid}"))?;
+        Ok(())
+    }
+
+    pub fn copy_source(&self, uuid: &str, source: &mut impl Read) -> Result<()> {
+        fs::create_dir_all(self.sources_directory()).context("Creating sources directory")?;
+        let mut file = File::create(self.source_path(uuid)).context(format!("Creating source of {uuid}"))?;
+        io::copy(source, &mut file).context(format!("Copying source of {uuid}"))?;
+        Ok(())
+    }
+
+    pub fn remove_source(&self, uuid: &str) -> Result<()> {
+        match fs::remove_file(self.source_path(uuid)) {
+            Ok(_) => Ok(()),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(error).context(format!("Removing source of {uuid}")),
+        }
+    }
+
+    // Sources of books that aren't in the library, left by a book that was still loading when the app was closed
+    pub fn remove_orphaned_sources(&self, stored_books: &[StoredBook]) -> Result<()> {
+        let directory = self.sources_directory();
+        let entries = match fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error).context(format!("Listing {}", directory.display())),
+        };
+        for entry in entries {
+            let path = entry.context(format!("Listing {}", directory.display()))?.path();
+            let Some(uuid) = path.file_stem().and_then(|file_stem| file_stem.to_str()) else {
+                continue;
+            };
+            if !stored_books.iter().any(|stored_book| stored_book.uuid == uuid) {
+                fs::remove_file(&path).context(format!("Removing {}", path.display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_json<T: DeserializeOwned>(&self, file_name: &str) -> Result<Option<T>> {
+        let path = self.root.join(file_name);
+        let json = match fs::read_to_string(&path) {
+            Ok(json) => json,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error).context(format!("Reading {}", path.display())),
+        };
+        let value = serde_json::from_str(&json).context(format!("Parsing {}", path.display()))?;
+        Ok(Some(value))
+    }
+
+    fn write_json<T: Serialize + ?Sized>(&self, file_name: &str, value: &T) -> Result<()> {
+        let path = self.root.join(file_name);
+        fs::create_dir_all(path.parent().unwrap_or(&self.root)).context("Creating library directory")?;
+        let json = serde_json::to_string(value)?;
+        // Write to a temporary file first so a crash mid-write never corrupts the previous version
+        let temporary_path = self.root.join(format!("{file_name}.tmp"));
+        fs::write(&temporary_path, json).context(format!("Writing {file_name}"))?;
+        fs::rename(&temporary_path, &path).context(format!("Replacing {file_name}"))?;
+        Ok(())
+    }
+
+    fn to_stored_book(book: &Book) -> Option<StoredBook> {
+        match &book.loading_state {
+            PdfLoadingState::ValidPdf { title, author, page_count, .. } => Some(StoredBook {
+                uuid: book.uuid.clone(),
+                title: title.clone(),
+                author: author.clone(),
+                page_count: *page_count,
+                metadata_overlay: book.metadata_overlay.clone(),
+                password_required: false,
+            }),
+            PdfLoadingState::PasswordRequired { file_name, .. } => Some(StoredBook {
+                uuid: book.uuid.clone(),
+                title: file_name.clone(),
+                author: String::new(),
+                page_count: 0,
+                metadata_overlay: book.metadata_overlay.clone(),
+                password_required: true,
+            }),
+            _ => None,
+        }
+    }
+
+    fn sources_directory(&self) -> PathBuf {
+        self.root.join(SOURCES_DIRECTORY_NAME)
+    }
+
+    pub fn render_cache_directory(&self) -> PathBuf {
+        self.root.join(RENDER_CACHE_DIRECTORY_NAME)
+    }
+
+    pub fn source_path(&self, uuid: &str) -> PathBuf {
+        self.sources_directory().join(format!("{uuid}.pdf"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{PageRect, PdfLoadingError};
+    use crate::test_support::TemporaryDirectory;
+
+    fn book(uuid: &str, loading_state: PdfLoadingState) -> Book {
+        Book { uuid: uuid.to_string(), thumbnail: None, loading_state, metadata_overlay: BookMetadataOverlay::default() }
+    }
+
+    fn valid_pdf(title: &str) -> PdfLoadingState {
+        PdfLoadingState::ValidPdf { title: title.to_string(), author: "Author".to_string(), thumbnail: None, page_count: 3 }
+    }
+
+    #[test]
+    fn the_library_survives_a_round_trip() {
+        let directory = TemporaryDirectory::new();
+        let storage = LibraryStorage::new(directory.path());
+        let mut renamed = book("renamed", valid_pdf("Renamed"));
+        renamed.metadata_overlay = BookMetadataOverlay { title: Some("New title".to_string()), author: None };
+        let books = vec![book("valid", valid_pdf("Valid")), renamed];
+        storage.save_books(&books, &[]).unwrap();
+        let restored_books: Vec<Book> = LibraryStorage::new(directory.path())
+            .load_books()
+            .unwrap()
+            .iter()
+            .map(StoredBook::to_book)
+            .collect();
+        assert!(restored_books == books);
+
+        let reading_positions = HashMap::from([("valid".to_string(), ReadingPosition { page_index: 2, scroll_offset: 0.5 })]);
+        let bookmarks = HashMap::from([("valid".to_string(), vec![Bookmark { page_index: 1, note: "Note".to_string() }])]);
+        let highlights = HashMap::from([("valid".to_string(), vec![Highlight {
+            highlight_id: "highlight".to_string(),
+            page_index: 0,
+            first_char_index: 4,
+            char_count: 5,
+            color: 0xFFFFFF00,
+            note: String::new(),
+            quoted_text: "quote".to_string(),
+            rects: vec![PageRect { left: 0.1, top: 0.1, right: 0.5, bottom: 0.15 }],
+        }])]);
+        storage.save_reading_positions(&reading_positions).unwrap();
+        storage.save_bookmarks(&bookmarks).unwrap();
+        storage.save_highlights(&highlights).unwrap();
+        assert_eq!(storage.load_reading_positions().unwrap(), reading_positions);
+        assert_eq!(storage.load_bookmarks().unwrap(), bookmarks);
+        assert_eq!(storage.load_highlights().unwrap(), highlights);
+    }
+
+    #[test]
+    fn books_that_failed_to_load_keep_their_entry_unless_their_source_is_broken() {
+        let directory = TemporaryDirectory::new();
+        let storage = LibraryStorage::new(directory.path());
+        let previous_books = storage
+            .save_books(&[book("unavailable", valid_pdf("Unavailable")), book("corrupt", valid_pdf("Corrupt"))], &[])
+            .unwrap();
+        let stored_books = storage.save_books(&[
+            book("unavailable", PdfLoadingState::ErrorPdf { error: PdfLoadingError::EngineUnavailable }),
+            book("corrupt", PdfLoadingState::ErrorPdf { error: PdfLoadingError::CorruptFile }),
+            book("loading", PdfLoadingState::LoadingPdf),
+        ], &previous_books).unwrap();
+        let stored_uuids: Vec<String> = storage.load_books().unwrap().into_iter().map(|stored_book| stored_book.uuid).collect();
+        assert_eq!(stored_uuids, vec!["unavailable"]);
+        assert_eq!(stored_books.len(), 1);
+    }
+
+    #[test]
+    fn sources_are_copied_and_removed() {
+        let directory = TemporaryDirectory::new();
+        let storage = LibraryStorage::new(directory.path());
+        storage.copy_source("book", &mut "%PDF-1.4".as_bytes()).unwrap();
+        assert_eq!(fs::read(storage.source_path("book")).unwrap(), b"%PDF-1.4");
+        storage.remove_source("book").unwrap();
+        assert!(!storage.source_path("book").exists());
+        storage.remove_source("book").unwrap();
+    }
+
+    #[test]
+    fn books_waiting_for_a_password_are_stored_until_they_open() {
+        let directory = TemporaryDirectory::new();
+        let storage = LibraryStorage::new(directory.path());
+        let encrypted = book("encrypted", PdfLoadingState::PasswordRequired { file_name: "secret.pdf".to_string(), wrong_password: true });
+        let previous_books = storage.save_books(&[encrypted], &[]).unwrap();
+        let restored_books: Vec<Book> = storage.load_books().unwrap().iter().map(StoredBook::to_book).collect();
+        assert!(restored_books == vec![book("encrypted", PdfLoadingState::PasswordRequired {
+            file_name: "secret.pdf".to_string(),
+            wrong_password: false,
+        })]);
+        storage.save_books(&[book("encrypted", valid_pdf("Secret"))], &previous_books).unwrap();
+        let restored_books: Vec<Book> = storage.load_books().unwrap().iter().map(StoredBook::to_book).collect();
+        assert!(restored_books == vec![book("encrypted", valid_pdf("Secret"))]);
+    }
+
+    #[test]
+    fn sources_of_books_outside_the_library_are_removed() {
+        let directory = TemporaryDirectory::new();
+        let storage = LibraryStorage::new(directory.path());
+        storage.remove_orphaned_sources(&[]).unwrap();
+        storage.save_source("kept", b"%PDF-1.4").unwrap();
+        storage.save_source("orphaned", b"%PDF-1.4").unwrap();
+        let stored_books = storage.save_books(&[book("kept", valid_pdf("Kept"))], &[]).unwrap();
+        storage.remove_orphaned_sources(&stored_books).unwrap();
+        assert!(storage.source_path("kept").exists());
+        assert!(!storage.source_path("orphaned").exists());
+    }
+
+    fn page_texts(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|text| text.to_string()).collect()
+    }
+
+    #[test]
+    fn book_indices_are_stored_separately() {
+        let directory = TemporaryDirectory::new();
+        let storage = LibraryStorage::new(directory.path());
+        storage.save_book_index("first", &page_texts(&["one", "two"])).unwrap();
+        storage.save_book_index("second", &page_texts(&["three"])).unwrap();
+        storage.remove_book_index("first").unwrap();
+        storage.remove_book_index("missing").unwrap();
+        let library_index = storage.load_library_index().unwrap();
+        assert_eq!(library_index, HashMap::from([("second".to_string(), page_texts(&["three"]))]));
+    }
+
+    #[test]
+    fn a_broken_book_index_is_skipped() {
+        let directory = TemporaryDirectory::new();
+        let storage = LibraryStorage::new(directory.path());
+        storage.save_book_index("valid", &page_texts(&["one"])).unwrap();
+        fs::write(directory.path().join(LibraryStorage::book_index_file_name("broken")), "{").unwrap();
+        let library_index = storage.load_library_index().unwrap();
+        assert_eq!(library_index.keys().collect::<Vec<_>>(), vec!["valid"]);
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use crate::domain::{Book, Bookmark, Highlight, NotesFormat, PdfLoadingState};
+
+#[derive(Serialize)]
+struct ExportedNotes<'a> {
+    title: String,
+    author: String,
+    page_count: i32,
+    pages: Vec<ExportedPage<'a>>,
+}
+
+#[derive(Serialize, Default)]
+struct ExportedPage<'a> {
+    page_index: i32,
+    bookmark_note: Option<&'a str>,
+    highlights: Vec<ExportedHighlight<'a>>,
+}
+
+#[derive(Serialize)]
+struct ExportedHighlight<'a> {
+    quoted_text: &'a str,
+    note: &'a str,
+    color: u32,
+}
+
+pub fn export_notes(book: &Book, bookmarks: &[Bookmark], highlights: &[Highlight], format: NotesFormat) -> Result<String> {
+    let notes = collect_notes(book, bookmarks, highlights);
+    match format {
+        NotesFormat::Markdown => Ok(to_markdown(&notes)),
+        NotesFormat::Json => serde_json::to_string_pretty(&notes).context("Serializing notes"),
+    }
+}
+
+fn collect_notes<'a>(book: &Book, bookmarks: &'a [Bookmark], highlights: &'a [Highlight]) -> ExportedNotes<'a> {
+    let mut pages: BTreeMap<i32, ExportedPage> = BTreeMap::new();
+    for bookmark in bookmarks {
+        let page = pages.entry(bookmark.page_index).or_default();
+        page.bookmark_note = Some(&bookmark.note);
+    }
+    let mut highlights: Vec<&Highlight> = highlights.iter().collect();
+    highlights.sort_by_key(|highlight| (highlight.page_index, highlight.first_char_index));
+    for highlight in highlights {
+        pages.entry(highlight.page_index).or_default().highlights.push(ExportedHighlight {
+            quoted_text: &highlight.quoted_text,
+            note: &highlight.note,
+            color: highlight.color,
+        });
+    }
+    let page_count = match &book.loading_state {
+        PdfLoadingState::ValidPdf { page_count, .. } => *page_count,
+        _ => 0,
+    };
+    ExportedNotes {
+        title: book.display_title(),
+        author: book.display_author(),
+        page_count,
+        pages: pages
+            .into_iter()
+            .map(|(page_index, page)| ExportedPage { page_index, ..page })
+            .collect(),
+    }
+}
+
+fn to_markdown(notes: &ExportedNotes) -> String {
+    let mut markdown = String::new();
+    // Writing into a String can't fail
+    let _ = writeln!(markdown, "# {}", notes.title);
+    if !notes.author.is_empty() {
+        let _ = writeln!(markdown, "\n*{}*", notes.author);
+    }
+    for page in &notes.pages {
+        let _ = writeln!(markdown, "\n## Page {}", page.page_index + 1);
+        if let Some(bookmark_note) = page.bookmark_note.filter(|note| !note.is_empty()) {
+            let _ = writeln!(markdown, "\n**Bookmark:** {bookmark_note}");
+        }
+        for highlight in &page.highlights {
+            let _ = writeln!(markdown, "\n> {}", highlight.quoted_text.split_whitespace().collect::<Vec<_>>().join(" "));
+            if !highlight.note.is_empty() {
+                let _ = writeln!(markdown, "\n{}", highlight.note);
+            }
+        }
+    }
+    markdown
+}
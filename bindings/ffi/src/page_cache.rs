@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::domain::{Bitmap, ImageEncoding};
+
+// Lossless, so compressed pages stay as sharp as the ones they replace
+const OFF_SCREEN_PAGE_ENCODING: ImageEncoding = ImageEncoding::Png;
+
+// What an insert did to the other pages - the encoded ones hold their images compressed now
+pub struct CacheChanges {
+    pub encoded: Vec<(i32, Arc<Bitmap>)>,
+    pub evicted: Vec<i32>,
+}
+
+struct CachedPage {
+    image: Arc<Bitmap>,
+    last_used: u64,
+}
+
+// Rendered page images of the open document, kept under a byte budget.
+// Pages furthest from the reading position get compressed first, and dropped once all of them are,
+// least recently used ones first among equally far pages.
+pub struct PageCache {
+    budget_bytes: u64,
+    used_bytes: u64,
+    pages: HashMap<i32, CachedPage>,
+    usage_counter: u64,
+}
+
+impl PageCache {
+    pub fn new(budget_bytes: u64) -> PageCache {
+        PageCache { budget_bytes, used_bytes: 0, pages: HashMap::new(), usage_counter: 0 }
+    }
+
+    pub fn clear(&mut self) {
+        self.pages.clear();
+        self.used_bytes = 0;
+    }
+
+    pub fn get(&mut self, page_index: i32) -> Option<Arc<Bitmap>> {
+        self.usage_counter += 1;
+        let page = self.pages.get_mut(&page_index)?;
+        page.last_used = self.usage_counter;
+        Some(page.image.clone())
+    }
+
+    // Like get, without counting as a use
+    pub fn peek(&self, page_index: i32) -> Option<Arc<Bitmap>> {
+        self.pages.get(&page_index).map(|page| page.image.clone())
+    }
+
+    // Makes room by compressing the pages further from focus_index first and only then dropping them.
+    // The page at focus_index stays as it is.
+    pub fn insert(&mut self, page_index: i32, image: Arc<Bitmap>, focus_index: i32) -> CacheChanges {
+        self.usage_counter += 1;
+        self.used_bytes += image.held_bytes();
+        let replaced = self.pages.insert(page_index, CachedPage { image, last_used: self.usage_counter });
+        if let Some(replaced) = replaced {
+            self.used_bytes -= replaced.image.held_bytes();
+        }
+        let mut changes = CacheChanges { encoded: vec![], evicted: vec![] };
+        while self.used_bytes > self.budget_bytes {
+            let picked = self
+                .pick(focus_index, |page| page.image.encoding().is_none())
+                .map(|page_index| (page_index, true))
+                .or_else(|| self.pick(focus_index, |_| true).map(|page_index| (page_index, false)));
+            let Some((evicted_index, is_raw)) = picked else {
+                break;
+            };
+            if is_raw {
+                if let Some(encoded) = self.encode(evicted_index) {
+                    changes.encoded.push((evicted_index, encoded));
+                    continue;
+                }
+            }
+            if let Some(evicted_page) = self.pages.remove(&evicted_index) {
+                self.used_bytes -= evicted_page.image.held_bytes();
+            }
+            changes.encoded.retain(|(encoded_index, _)| *encoded_index != evicted_index);
+            changes.evicted.push(evicted_index);
+        }
+        changes
+    }
+
+    // Swaps the page image for its encoded version - a page that fails to encode gets evicted instead
+    fn encode(&mut self, page_index: i32) -> Option<Arc<Bitmap>> {
+        let page = self.pages.get_mut(&page_index)?;
+        match page.image.encode(OFF_SCREEN_PAGE_ENCODING) {
+            Ok(encoded) => {
+                self.used_bytes = self.used_bytes - page.image.held_bytes() + encoded.held_bytes();
+                page.image = encoded.clone();
+                Some(encoded)
+            }
+            Err(error) => {
+                error!("PageCache - error encoding page {page_index} - {error}");
+                None
+            }
+        }
+    }
+
+    fn pick(&self, focus_index: i32, filter: impl Fn(&CachedPage) -> bool) -> Option<i32> {
+        self.pages
+            .iter()
+            .filter(|(page_index, page)| **page_index != focus_index && filter(page))
+            .max_by_key(|(page_index, page)| ((**page_index - focus_index).abs(), u64::MAX - page.last_used))
+            .map(|(page_index, _)| *page_index)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::PixelFormat;
+
+    const PAGE_SIZE: i32 = 100;
+    const RAW_PAGE_BYTES: u64 = (PAGE_SIZE * PAGE_SIZE * 4) as u64;
+
+    fn raw_page() -> Arc<Bitmap> {
+        Bitmap::from_rgba(PAGE_SIZE, PAGE_SIZE, vec![255; RAW_PAGE_BYTES as usize], PixelFormat::Rgba)
+    }
+
+    fn assert_accounted(cache: &PageCache) {
+        assert_eq!(cache.used_bytes, cache.pages.values().map(|page| page.image.held_bytes()).sum::<u64>());
+    }
+
+    #[test]
+    fn pages_furthest_from_the_focus_are_compressed_first() {
+        let mut cache = PageCache::new(3 * RAW_PAGE_BYTES + RAW_PAGE_BYTES / 10);
+        for page_index in 0..4 {
+            cache.insert(page_index, raw_page(), 1);
+        }
+        let encodings: Vec<Option<ImageEncoding>> = (0..4).map(|page_index| cache.peek(page_index).unwrap().encoding()).collect();
+        assert_eq!(encodings, vec![None, None, None, Some(ImageEncoding::Png)]);
+        assert_accounted(&cache);
+    }
+
+    #[test]
+    fn pages_are_compressed_before_any_is_dropped() {
+        let mut cache = PageCache::new(2 * RAW_PAGE_BYTES);
+        for page_index in 0..4 {
+            cache.insert(page_index, raw_page(), 1);
+            assert_accounted(&cache);
+            assert!(cache.used_bytes <= 2 * RAW_PAGE_BYTES);
+        }
+        // Only the focused page is left raw, compressed pages still taking a little of the budget, none dropped
+        let encodings: Vec<Option<ImageEncoding>> = (0..4).map(|page_index| cache.peek(page_index).unwrap().encoding()).collect();
+        assert_eq!(encodings, vec![Some(ImageEncoding::Png), None, Some(ImageEncoding::Png), Some(ImageEncoding::Png)]);
+    }
+
+    #[test]
+    fn pages_are_dropped_once_all_are_compressed() {
+        let mut cache = PageCache::new(RAW_PAGE_BYTES);
+        cache.insert(0, raw_page(), 0);
+        let changes = cache.insert(1, raw_page(), 0);
+        assert!(changes.encoded.is_empty());
+        assert_eq!(changes.evicted, vec![1]);
+        assert!(cache.peek(1).is_none());
+        assert_eq!(cache.used_bytes, RAW_PAGE_BYTES);
+    }
+
+    #[test]
+    fn the_focused_page_stays_even_past_the_budget() {
+        let mut cache = PageCache::new(1);
+        let changes = cache.insert(0, raw_page(), 0);
+        assert!(changes.encoded.is_empty() && changes.evicted.is_empty());
+        assert!(cache.peek(0).unwrap().encoding().is_none());
+        assert_eq!(cache.used_bytes, RAW_PAGE_BYTES);
+    }
+
+    #[test]
+    fn replaced_pages_are_only_counted_once() {
+        let mut cache = PageCache::new(10 * RAW_PAGE_BYTES);
+        cache.insert(0, raw_page(), 0);
+        cache.insert(0, raw_page(), 0);
+        assert_eq!(cache.used_bytes, RAW_PAGE_BYTES);
+        cache.clear();
+        assert_eq!(cache.used_bytes, 0);
+    }
+
+    #[test]
+    fn the_budget_holds_after_the_host_reads_compressed_pages() {
+        let budget_bytes = 2 * RAW_PAGE_BYTES;
+        let mut cache = PageCache::new(budget_bytes);
+        for page_index in 0..4 {
+            cache.insert(page_index, raw_page(), 0);
+        }
+        for page_index in 0..4 {
+            let image = cache.peek(page_index).unwrap();
+            // The host reads compressed pages by decoding them into memory of its own
+            assert_eq!(image.copy_pixels().len() as u64, RAW_PAGE_BYTES / 4);
+            if image.encoding().is_some() {
+                assert_eq!(image.pixels_address(), 0);
+            }
+        }
+        let held_bytes: u64 = (0..4).map(|page_index| cache.peek(page_index).unwrap().held_bytes()).sum();
+        assert!(held_bytes <= budget_bytes);
+        assert_accounted(&cache);
+    }
+}
@@ -0,0 +1,182 @@
+use pdfium_render::prelude::PdfPageRenderRotation;
+use crate::domain::{FitMode, PageRotation};
+
+// Previews are rendered this many times smaller than the page layout size
+const PREVIEW_SCALE_DOWN: i32 = 4;
+// Pages this big already take 64MB as ARGB - anything more risks running out of memory
+const MAX_RENDER_PIXELS: i64 = 4096 * 4096;
+
+// How pages are fitted into a box of box_width x box_height pixels
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PageLayout {
+    pub box_width: i32,
+    pub box_height: i32,
+    pub fit_mode: FitMode,
+    pub rotation: PageRotation,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RenderSize {
+    pub width: i32,
+    pub height: i32,
+}
+
+impl PageLayout {
+    pub fn preview(&self) -> PageLayout {
+        PageLayout {
+            box_width: (self.box_width / PREVIEW_SCALE_DOWN).max(1),
+            box_height: (self.box_height / PREVIEW_SCALE_DOWN).max(1),
+            ..*self
+        }
+    }
+
+    // Exact pixel size of a page_width x page_height page (in any unit) laid out in the box,
+    // with the fitted side matching the box exactly
+    pub fn fit(&self, page_width: f32, page_height: f32) -> RenderSize {
+        let (page_width, page_height) = match self.rotation {
+            PageRotation::Degrees90 | PageRotation::Degrees270 => (page_height, page_width),
+            PageRotation::None | PageRotation::Degrees180 => (page_width, page_height),
+        };
+        if !(page_width > 0.0 && page_height > 0.0) {
+            return RenderSize { width: 1, height: 1 };
+        }
+        let page_ratio = page_height / page_width;
+        let box_width = self.box_width.max(1);
+        let box_height = self.box_height.max(1);
+        let fit_width = match self.fit_mode {
+            FitMode::Width => true,
+            FitMode::Height => false,
+            FitMode::WholePage => page_ratio <= box_height as f32 / box_width as f32,
+        };
+        let size = if fit_width {
+            RenderSize { width: box_width, height: to_pixels(box_width as f32 * page_ratio) }
+        } else {
+            RenderSize { width: to_pixels(box_height as f32 / page_ratio), height: box_height }
+        };
+        Self::limit_pixels(size, page_ratio)
+    }
+
+    pub fn pdfium_rotation(&self) -> PdfPageRenderRotation {
+        match self.rotation {
+            PageRotation::None => PdfPageRenderRotation::None,
+            PageRotation::Degrees90 => PdfPageRenderRotation::Degrees90,
+            PageRotation::Degrees180 => PdfPageRenderRotation::Degrees180,
+            PageRotation::Degrees270 => PdfPageRenderRotation::Degrees270,
+        }
+    }
+
+    fn limit_pixels(size: RenderSize, page_ratio: f32) -> RenderSize {
+        if i64::from(size.width) * i64::from(size.height) <= MAX_RENDER_PIXELS {
+            return size;
+        }
+        let width = to_pixels((MAX_RENDER_PIXELS as f32 / page_ratio).sqrt());
+        RenderSize { width, height: to_pixels(width as f32 * page_ratio) }
+    }
+}
+
+fn to_pixels(value: f32) -> i32 {
+    (value.round() as i32).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::FIXTURE_PAGES;
+
+    const FIT_MODES: [FitMode; 3] = [FitMode::Width, FitMode::Height, FitMode::WholePage];
+    const ROTATIONS: [PageRotation; 4] = [PageRotation::None, PageRotation::Degrees90, PageRotation::Degrees180, PageRotation::Degrees270];
+
+    fn layout(fit_mode: FitMode, rotation: PageRotation) -> PageLayout {
+        PageLayout { box_width: 1000, box_height: 1600, fit_mode, rotation }
+    }
+
+    // The page sides as displayed, after the layout rotation
+    fn displayed_sides(page_width: f32, page_height: f32, rotation: PageRotation) -> (f32, f32) {
+        match rotation {
+            PageRotation::Degrees90 | PageRotation::Degrees270 => (page_height, page_width),
+            PageRotation::None | PageRotation::Degrees180 => (page_width, page_height),
+        }
+    }
+
+    #[test]
+    fn fitted_side_matches_the_box() {
+        for page in &FIXTURE_PAGES {
+            for fit_mode in FIT_MODES {
+                for rotation in ROTATIONS {
+                    let page_layout = layout(fit_mode, rotation);
+                    let size = page_layout.fit(page.width, page.height);
+                    let context = format!("{} {fit_mode:?} {rotation:?} -> {size:?}", page.name);
+                    let pixels = i64::from(size.width) * i64::from(size.height);
+                    assert!(pixels <= MAX_RENDER_PIXELS, "{context}");
+                    // Too big to fit the box - scaled down to about the pixel limit instead
+                    if pixels > MAX_RENDER_PIXELS * 99 / 100 {
+                        continue;
+                    }
+                    match fit_mode {
+                        FitMode::Width => assert_eq!(size.width, page_layout.box_width, "{context}"),
+                        FitMode::Height => assert_eq!(size.height, page_layout.box_height, "{context}"),
+                        FitMode::WholePage => {
+                            assert!(size.width <= page_layout.box_width && size.height <= page_layout.box_height, "{context}");
+                            assert!(size.width == page_layout.box_width || size.height == page_layout.box_height, "{context}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn aspect_ratio_is_kept() {
+        for page in &FIXTURE_PAGES {
+            for fit_mode in FIT_MODES {
+                for rotation in ROTATIONS {
+                    let size = layout(fit_mode, rotation).fit(page.width, page.height);
+                    let (displayed_width, displayed_height) = displayed_sides(page.width, page.height, rotation);
+                    let expected_height = size.width as f32 * displayed_height / displayed_width;
+                    assert!(
+                        (size.height as f32 - expected_height).abs() <= 1.0,
+                        "{} {fit_mode:?} {rotation:?} -> {size:?}, expected height {expected_height}",
+                        page.name,
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn quarter_turns_swap_the_page_sides() {
+        for page in &FIXTURE_PAGES {
+            for fit_mode in FIT_MODES {
+                let upright = layout(fit_mode, PageRotation::None).fit(page.height, page.width);
+                assert_eq!(layout(fit_mode, PageRotation::Degrees90).fit(page.width, page.height), upright);
+                assert_eq!(layout(fit_mode, PageRotation::Degrees270).fit(page.width, page.height), upright);
+                let turned = layout(fit_mode, PageRotation::Degrees180).fit(page.width, page.height);
+                assert_eq!(turned, layout(fit_mode, PageRotation::None).fit(page.width, page.height));
+            }
+        }
+    }
+
+    #[test]
+    fn huge_renders_are_limited() {
+        let page_layout = PageLayout { box_width: 20_000, box_height: 1600, fit_mode: FitMode::Width, rotation: PageRotation::None };
+        let size = page_layout.fit(300.0, 3000.0);
+        assert!(i64::from(size.width) * i64::from(size.height) <= MAX_RENDER_PIXELS, "{size:?}");
+        assert!((size.height as f32 - size.width as f32 * 10.0).abs() <= 10.0, "{size:?}");
+    }
+
+    #[test]
+    fn previews_are_scaled_down() {
+        let page_layout = layout(FitMode::Width, PageRotation::None);
+        let size = page_layout.fit(612.0, 792.0);
+        let preview_size = page_layout.preview().fit(612.0, 792.0);
+        assert_eq!(preview_size.width, size.width / PREVIEW_SCALE_DOWN);
+        assert_eq!(PageLayout { box_width: 2, box_height: 2, ..page_layout }.preview().box_width, 1);
+    }
+
+    #[test]
+    fn degenerate_pages_take_a_single_pixel() {
+        let page_layout = layout(FitMode::WholePage, PageRotation::None);
+        assert_eq!(page_layout.fit(0.0, 792.0), RenderSize { width: 1, height: 1 });
+        assert_eq!(page_layout.fit(f32::NAN, 792.0), RenderSize { width: 1, height: 1 });
+    }
+}
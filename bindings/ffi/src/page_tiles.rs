@@ -0,0 +1,239 @@
+use std::collections::{HashMap, VecDeque};
+use anyhow::Result;
+use pdfium_render::prelude::*;
+use crate::domain::{Bitmap, PageRect, PageRotation, PixelFormat, Tile};
+use crate::page_layout::PageLayout;
+
+pub const TILE_SIZE: i32 = 512;
+pub const MAX_TILE_ZOOM_LEVEL: i32 = 4;
+const MAX_CACHED_TILES: usize = 128;
+// A single request never renders more than a screenful or two of tiles
+const MAX_TILES_PER_REQUEST: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct TileKey {
+    page_index: i32,
+    zoom_level: i32,
+    column: i32,
+    row: i32,
+}
+
+// Least recently used tiles are dropped first
+#[derive(Default)]
+pub struct TileCache {
+    tiles: HashMap<TileKey, Tile>,
+    usage: VecDeque<TileKey>,
+}
+
+impl TileCache {
+    pub fn clear(&mut self) {
+        self.tiles.clear();
+        self.usage.clear();
+    }
+
+    fn get(&mut self, key: &TileKey) -> Option<Tile> {
+        let tile = self.tiles.get(key)?.clone();
+        self.touch(*key);
+        Some(tile)
+    }
+
+    fn insert(&mut self, key: TileKey, tile: Tile) {
+        self.tiles.insert(key, tile);
+        self.touch(key);
+        while self.usage.len() > MAX_CACHED_TILES {
+            if let Some(evicted_key) = self.usage.pop_front() {
+                self.tiles.remove(&evicted_key);
+            }
+        }
+    }
+
+    fn touch(&mut self, key: TileKey) {
+        self.usage.retain(|used_key| *used_key != key);
+        self.usage.push_back(key);
+    }
+}
+
+// Returns the tiles covering region at zoom_level, where zoom level z renders the page 2^z times bigger than the layout does.
+// The region and the tiles are fractions of the page as displayed, turned by the layout rotation.
+pub fn load_tiles(
+    page: &PdfPage,
+    page_index: i32,
+    zoom_level: i32,
+    region: &PageRect,
+    page_layout: &PageLayout,
+    pixel_format: PixelFormat,
+    tile_cache: &mut TileCache,
+) -> Result<Vec<Tile>> {
+    let page_size = page_layout.fit(page.width().value, page.height().value);
+    let page_pixel_width = page_size.width << zoom_level;
+    let page_pixel_height = page_size.height << zoom_level;
+    let columns = tile_range(region.left, region.right, page_pixel_width);
+    let rows = tile_range(region.top, region.bottom, page_pixel_height);
+    let mut tiles = vec![];
+    for row in rows {
+        for column in columns.clone() {
+            if tiles.len() >= MAX_TILES_PER_REQUEST {
+                return Ok(tiles);
+            }
+            let key = TileKey { page_index, zoom_level, column, row };
+            let tile = match tile_cache.get(&key) {
+                Some(tile) => tile,
+                None => {
+                    let tile = render_tile(page, page_pixel_width, page_pixel_height, key, page_layout.rotation, pixel_format)?;
+                    tile_cache.insert(key, tile.clone());
+                    tile
+                }
+            };
+            tiles.push(tile);
+        }
+    }
+    Ok(tiles)
+}
+
+fn tile_range(start: f32, end: f32, page_pixels: i32) -> std::ops::Range<i32> {
+    let last_tile = (page_pixels - 1) / TILE_SIZE;
+    let first = ((start.clamp(0.0, 1.0) * page_pixels as f32) as i32 / TILE_SIZE).min(last_tile);
+    let last = ((end.clamp(0.0, 1.0) * page_pixels as f32) as i32 / TILE_SIZE).min(last_tile);
+    first..last + 1
+}
+
+// page_pixel_width and page_pixel_height are the sides of the page as displayed
+fn render_tile(
+    page: &PdfPage,
+    page_pixel_width: i32,
+    page_pixel_height: i32,
+    key: TileKey,
+    rotation: PageRotation,
+    pixel_format: PixelFormat,
+) -> Result<Tile> {
+    let left = key.column * TILE_SIZE;
+    let top = key.row * TILE_SIZE;
+    // Edge tiles are cut to the page
+    let width = TILE_SIZE.min(page_pixel_width - left);
+    let height = TILE_SIZE.min(page_pixel_height - top);
+    // The tile is cut from the page before it's turned, then turned along with it
+    let (unrotated_left, unrotated_top, unrotated_width, unrotated_height) =
+        unrotated_rect(left, top, width, height, page_pixel_width, page_pixel_height, rotation);
+    let unrotated_page_pixel_width = match rotation {
+        PageRotation::Degrees90 | PageRotation::Degrees270 => page_pixel_height,
+        PageRotation::None | PageRotation::Degrees180 => page_pixel_width,
+    };
+    let scale = unrotated_page_pixel_width as f32 / page.width().value;
+    // Shifts the page so the tile's top left corner lands at the bitmap origin - pdfium skips everything outside the bitmap
+    let config = PdfRenderConfig::new()
+        .set_target_width(unrotated_page_pixel_width)
+        .translate(PdfPoints::new(-unrotated_left as f32 / scale), PdfPoints::new(-unrotated_top as f32 / scale))?;
+    let mut pdf_bitmap = PdfBitmap::empty(unrotated_width, unrotated_height, PdfBitmapFormat::default(), page.bindings())?;
+    page.render_into_bitmap_with_config(&mut pdf_bitmap, &config)?;
+    let rgba_bytes = rotate_pixels(pdf_bitmap.as_raw_bytes(), unrotated_width, unrotated_height, rotation);
+    let image = Bitmap::from_rgba(width, height, rgba_bytes, pixel_format);
+    Ok(Tile {
+        zoom_level: key.zoom_level,
+        column: key.column,
+        row: key.row,
+        rect: PageRect {
+            left: left as f32 / page_pixel_width as f32,
+            top: top as f32 / page_pixel_height as f32,
+            right: (left + width) as f32 / page_pixel_width as f32,
+            bottom: (top + height) as f32 / page_pixel_height as f32,
+        },
+        image,
+    })
+}
+
+// Where the rect at left, top of the page displayed turned clockwise by rotation lies on the unturned page,
+// as its left, top, width and height
+fn unrotated_rect(
+    left: i32,
+    top: i32,
+    width: i32,
+    height: i32,
+    page_pixel_width: i32,
+    page_pixel_height: i32,
+    rotation: PageRotation,
+) -> (i32, i32, i32, i32) {
+    match rotation {
+        PageRotation::None => (left, top, width, height),
+        PageRotation::Degrees90 => (top, page_pixel_width - left - width, height, width),
+        PageRotation::Degrees180 => (page_pixel_width - left - width, page_pixel_height - top - height, width, height),
+        PageRotation::Degrees270 => (page_pixel_height - top - height, left, height, width),
+    }
+}
+
+// Turns 4 byte pixels, width wide and height high, clockwise by rotation
+fn rotate_pixels(pixels: Vec<u8>, width: i32, height: i32, rotation: PageRotation) -> Vec<u8> {
+    if rotation == PageRotation::None {
+        return pixels;
+    }
+    let (width, height) = (width as usize, height as usize);
+    let mut rotated = vec![0; pixels.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let (rotated_x, rotated_y, rotated_width) = match rotation {
+                PageRotation::Degrees90 => (height - 1 - y, x, height),
+                PageRotation::Degrees180 => (width - 1 - x, height - 1 - y, width),
+                PageRotation::Degrees270 => (y, width - 1 - x, height),
+                PageRotation::None => (x, y, width),
+            };
+            let source = (y * width + x) * 4;
+            let target = (rotated_y * rotated_width + rotated_x) * 4;
+            rotated[target..target + 4].copy_from_slice(&pixels[source..source + 4]);
+        }
+    }
+    rotated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROTATIONS: [PageRotation; 4] = [PageRotation::None, PageRotation::Degrees90, PageRotation::Degrees180, PageRotation::Degrees270];
+
+    // Pixels of a width by height image, each holding its own coordinates
+    fn numbered_pixels(width: i32, height: i32) -> Vec<u8> {
+        (0..height).flat_map(|y| (0..width).flat_map(move |x| [x as u8, y as u8, 0, 255])).collect()
+    }
+
+    // A tile cut from the turned page holds what the turned page shows there
+    #[test]
+    fn tiles_of_a_turned_page_are_cut_from_where_it_displays_them() {
+        let (unrotated_width, unrotated_height) = (6, 4);
+        let unrotated_page = numbered_pixels(unrotated_width, unrotated_height);
+        for rotation in ROTATIONS {
+            let (page_pixel_width, page_pixel_height) = match rotation {
+                PageRotation::Degrees90 | PageRotation::Degrees270 => (unrotated_height, unrotated_width),
+                PageRotation::None | PageRotation::Degrees180 => (unrotated_width, unrotated_height),
+            };
+            let displayed_page = rotate_pixels(unrotated_page.clone(), unrotated_width, unrotated_height, rotation);
+            let (left, top, width, height) = (1, 2, 2, 1);
+            let (cut_left, cut_top, cut_width, cut_height) =
+                unrotated_rect(left, top, width, height, page_pixel_width, page_pixel_height, rotation);
+            let cut: Vec<u8> = (cut_top..cut_top + cut_height)
+                .flat_map(|y| (cut_left..cut_left + cut_width).map(move |x| (y, x)))
+                .flat_map(|(y, x)| {
+                    let index = ((y * unrotated_width + x) * 4) as usize;
+                    unrotated_page[index..index + 4].to_vec()
+                })
+                .collect();
+            let tile = rotate_pixels(cut, cut_width, cut_height, rotation);
+            let expected: Vec<u8> = (top..top + height)
+                .flat_map(|y| (left..left + width).map(move |x| (y, x)))
+                .flat_map(|(y, x)| {
+                    let index = ((y * page_pixel_width + x) * 4) as usize;
+                    displayed_page[index..index + 4].to_vec()
+                })
+                .collect();
+            assert_eq!(tile, expected, "{rotation:?}");
+        }
+    }
+
+    #[test]
+    fn pixels_turn_clockwise() {
+        // 2 by 1: a b turned 90 degrees clockwise is a above b
+        let pixels = numbered_pixels(2, 1);
+        assert_eq!(rotate_pixels(pixels.clone(), 2, 1, PageRotation::Degrees90), pixels);
+        let turned_back: Vec<u8> = [pixels[4..8].to_vec(), pixels[0..4].to_vec()].concat();
+        assert_eq!(rotate_pixels(pixels.clone(), 2, 1, PageRotation::Degrees270), turned_back);
+        assert_eq!(rotate_pixels(pixels, 2, 1, PageRotation::Degrees180), turned_back);
+    }
+}
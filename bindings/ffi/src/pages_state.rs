@@ -1,25 +1,55 @@
 use std::collections::HashMap;
 use std::string::ToString;
 use std::sync::{Arc, Mutex};
-use crate::domain::{Book, Page};
-use crate::global_state::{GlobalAction, GlobalState, GlobalStateListener, GlobalStore};
+use crate::bookmarks::export_bookmarks;
+use crate::domain::{Book, Bookmark, EngineStatus, FitMode, OutlineEntry, Page, PageRect, PageRotation, PageSelection, ReadingPosition, SearchState, Viewport};
+use crate::global_state::{GlobalAction, GlobalSideEffect, GlobalState, GlobalStateListener, GlobalStore};
 
 #[derive(Clone)]
 pub struct PagesState {
     pub current_book: Option<Book>,
     pub current_book_pages: Vec<Arc<Page>>,
+    pub current_book_outline: Vec<OutlineEntry>,
+    pub current_reading_position: Option<ReadingPosition>,
+    pub bookmarks: Vec<Bookmark>,
+    pub search: Option<SearchState>,
+    pub engine_status: EngineStatus,
+}
+
+#[derive(Clone)]
+pub enum PagesSideEffect {
+    HighlightFailed { page_index: i32 },
+    RenderingFailed { page_index: i32 },
 }
 
 pub enum PagesAction {
     LoadPage { page_index: i32 },
+    LoadTiles { page_index: i32, zoom_level: i32, region: PageRect },
+    UpdateReadingPosition { page_index: i32, scroll_offset: f32 },
+    SetViewport { width: f32, height: f32, zoom: f32, device_scale: f32 },
+    SetPageLayout { fit_mode: FitMode, rotation: PageRotation },
+    GoToOutlineEntry { entry: OutlineEntry },
+    AddBookmark { page_index: i32, note: String },
+    RemoveBookmark { page_index: i32 },
+    ImportBookmarks { json: String },
+    AddHighlight { page_index: i32, selection: PageSelection, color: u32, note: String },
+    RemoveHighlight { highlight_id: String },
+    EditHighlightNote { highlight_id: String, note: String },
+    Search { query: String },
+    CancelSearch,
 }
 
 pub enum PagesResult {
     PagesListUpdated { pages: Vec<Arc<Page>> },
+    CurrentBookUpdated { book: Option<Book>, outline: Vec<OutlineEntry>, reading_position: Option<ReadingPosition> },
+    SearchUpdated { search: Option<SearchState> },
+    BookmarksUpdated { bookmarks: Vec<Bookmark> },
+    EngineStatusUpdated { engine_status: EngineStatus },
 }
 
 pub trait PagesStateListener: Send + Sync {
     fn new_state(&self, state: PagesState);
+    fn new_side_effect(&self, side_effect: PagesSideEffect);
 }
 
 const PAGES_GLOBAL_STORE_LISTENER_ID: &str = "PAGES_GLOBAL_STORE_LISTENER_ID";
@@ -29,17 +59,25 @@ pub struct PagesStore {
     state: Mutex<PagesState>,
     listeners: Mutex<HashMap<String, Box<dyn PagesStateListener>>>,
     // cache
-    last_global_state: Option<GlobalState>,
+    last_global_state: Mutex<Option<GlobalState>>,
 }
 
 impl PagesStore {
     pub fn new(global_store: Arc<GlobalStore>) -> Self {
-        let initial_state = PagesState { current_book: None, current_book_pages: vec![] };
+        let initial_state = PagesState {
+            current_book: None,
+            current_book_pages: vec![],
+            current_book_outline: vec![],
+            current_reading_position: None,
+            bookmarks: vec![],
+            search: None,
+            engine_status: EngineStatus::Initializing,
+        };
         Self {
             global_store: Mutex::new(global_store),
             state: Mutex::new(initial_state),
             listeners: Mutex::new(HashMap::new()),
-            last_global_state: None,
+            last_global_state: Mutex::new(None),
         }
     }
 
@@ -65,6 +103,111 @@ impl PagesStore {
                     .clone()
                     .dispatch_action(GlobalAction::LoadPage { page_index })
             }
+            PagesAction::LoadTiles { page_index, zoom_level, region } => {
+                self.global_store
+                    .lock()
+                    .unwrap()
+                    .clone()
+                    .dispatch_action(GlobalAction::LoadTiles { page_index, zoom_level, region })
+            }
+            PagesAction::UpdateReadingPosition { page_index, scroll_offset } => {
+                self.global_store
+                    .lock()
+                    .unwrap()
+                    .clone()
+                    .dispatch_action(GlobalAction::UpdateReadingPosition { page_index, scroll_offset })
+            }
+            PagesAction::SetViewport { width, height, zoom, device_scale } => {
+                self.global_store
+                    .lock()
+                    .unwrap()
+                    .clone()
+                    .dispatch_action(GlobalAction::SetViewport { viewport: Viewport { width, height, zoom, device_scale } })
+            }
+            PagesAction::SetPageLayout { fit_mode, rotation } => {
+                self.global_store
+                    .lock()
+                    .unwrap()
+                    .clone()
+                    .dispatch_action(GlobalAction::SetPageLayout { fit_mode, rotation })
+            }
+            PagesAction::GoToOutlineEntry { entry } => {
+                let Some(page_index) = entry.page_index else {
+                    return;
+                };
+                self.global_store
+                    .lock()
+                    .unwrap()
+                    .clone()
+                    .dispatch_action(GlobalAction::GoToPage { page_index })
+            }
+            PagesAction::AddBookmark { page_index, note } => {
+                self.global_store
+                    .lock()
+                    .unwrap()
+                    .clone()
+                    .dispatch_action(GlobalAction::AddBookmark { page_index, note })
+            }
+            PagesAction::RemoveBookmark { page_index } => {
+                self.global_store
+                    .lock()
+                    .unwrap()
+                    .clone()
+                    .dispatch_action(GlobalAction::RemoveBookmark { page_index })
+            }
+            PagesAction::ImportBookmarks { json } => {
+                self.global_store
+                    .lock()
+                    .unwrap()
+                    .clone()
+                    .dispatch_action(GlobalAction::ImportBookmarks { json })
+            }
+            PagesAction::AddHighlight { page_index, selection, color, note } => {
+                self.global_store
+                    .lock()
+                    .unwrap()
+                    .clone()
+                    .dispatch_action(GlobalAction::AddHighlight { page_index, selection, color, note })
+            }
+            PagesAction::RemoveHighlight { highlight_id } => {
+                self.global_store
+                    .lock()
+                    .unwrap()
+                    .clone()
+                    .dispatch_action(GlobalAction::RemoveHighlight { highlight_id })
+            }
+            PagesAction::EditHighlightNote { highlight_id, note } => {
+                self.global_store
+                    .lock()
+                    .unwrap()
+                    .clone()
+                    .dispatch_action(GlobalAction::EditHighlightNote { highlight_id, note })
+            }
+            PagesAction::Search { query } => {
+                self.global_store
+                    .lock()
+                    .unwrap()
+                    .clone()
+                    .dispatch_action(GlobalAction::Search { query })
+            }
+            PagesAction::CancelSearch => {
+                self.global_store
+                    .lock()
+                    .unwrap()
+                    .clone()
+                    .dispatch_action(GlobalAction::CancelSearch)
+            }
+        }
+    }
+
+    // Json of the current book bookmarks, ready to be shared and imported with ImportBookmarks
+    pub fn export_bookmarks(&self) -> String {
+        match export_bookmarks(&self.state.lock().unwrap().bookmarks) {
+            Ok(json) => json,
+            Err(error) => {
+                error!("PagesStore::export_bookmarks error - {error}");
+                String::new()
+            }
         }
     }
 
@@ -84,6 +227,34 @@ impl PagesStore {
                 new_state.current_book_pages = pages;
                 new_state
             }
+            PagesResult::CurrentBookUpdated { book, outline, reading_position } => {
+                let mut new_state = state.clone();
+                new_state.current_book = book;
+                new_state.current_book_outline = outline;
+                new_state.current_reading_position = reading_position;
+                new_state
+            }
+            PagesResult::SearchUpdated { search } => {
+                let mut new_state = state.clone();
+                new_state.search = search;
+                new_state
+            }
+            PagesResult::BookmarksUpdated { bookmarks } => {
+                let mut new_state = state.clone();
+                new_state.bookmarks = bookmarks;
+                new_state
+            }
+            PagesResult::EngineStatusUpdated { engine_status } => {
+                let mut new_state = state.clone();
+                new_state.engine_status = engine_status;
+                new_state
+            }
+        }
+    }
+
+    fn dispatch_side_effect(&self, side_effect: PagesSideEffect) {
+        for listener in self.listeners.lock().unwrap().values() {
+            listener.new_side_effect(side_effect.clone());
         }
     }
 }
@@ -96,12 +267,51 @@ impl Drop for PagesStore {
 
 impl GlobalStateListener for Arc<PagesStore> {
     fn new_state(&self, new_global_state: GlobalState) {
-        let Some(last_global_state) = &self.last_global_state  else {
-            self.clone().process_result(PagesResult::PagesListUpdated { pages: new_global_state.current_book_pages.clone() });
-            return;
+        let mut last_global_state = self.last_global_state.lock().unwrap();
+        let (book_changed, pages_changed, search_changed, bookmarks_changed, engine_status_changed) = match last_global_state.as_ref() {
+            None => (true, true, true, true, true),
+            Some(last_global_state) => (
+                last_global_state.current_book != new_global_state.current_book
+                    || last_global_state.current_book_outline != new_global_state.current_book_outline
+                    || last_global_state.current_reading_position() != new_global_state.current_reading_position(),
+                last_global_state.current_book_pages != new_global_state.current_book_pages,
+                last_global_state.current_search != new_global_state.current_search,
+                last_global_state.current_bookmarks() != new_global_state.current_bookmarks(),
+                last_global_state.engine_status != new_global_state.engine_status,
+            ),
         };
-        if last_global_state.current_book_pages != new_global_state.current_book_pages {
+        if book_changed {
+            self.clone().process_result(PagesResult::CurrentBookUpdated {
+                book: new_global_state.current_book.clone(),
+                outline: new_global_state.current_book_outline.clone(),
+                reading_position: new_global_state.current_reading_position(),
+            });
+        }
+        if pages_changed {
             self.clone().process_result(PagesResult::PagesListUpdated { pages: new_global_state.current_book_pages.clone() });
         }
+        if search_changed {
+            self.clone().process_result(PagesResult::SearchUpdated { search: new_global_state.current_search.clone() });
+        }
+        if bookmarks_changed {
+            self.clone().process_result(PagesResult::BookmarksUpdated { bookmarks: new_global_state.current_bookmarks() });
+        }
+        if engine_status_changed {
+            self.clone().process_result(PagesResult::EngineStatusUpdated { engine_status: new_global_state.engine_status.clone() });
+        }
+        *last_global_state = Some(new_global_state);
+    }
+
+    // Exported files are handed over to the host by the books screen
+    fn new_side_effect(&self, side_effect: GlobalSideEffect) {
+        match side_effect {
+            GlobalSideEffect::HighlightFailed { page_index } => {
+                self.dispatch_side_effect(PagesSideEffect::HighlightFailed { page_index })
+            }
+            GlobalSideEffect::RenderingFailed { page_index } => {
+                self.dispatch_side_effect(PagesSideEffect::RenderingFailed { page_index })
+            }
+            GlobalSideEffect::FileExported { .. } | GlobalSideEffect::NotesExported { .. } | GlobalSideEffect::ExportFailed { .. } => {}
+        }
     }
 }
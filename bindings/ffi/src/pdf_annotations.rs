@@ -0,0 +1,98 @@
+use anyhow::Result;
+use pdfium_render::prelude::*;
+use crate::domain::{Bookmark, Highlight, PageRect};
+use crate::pdf_text::fraction_to_points;
+
+const NOTE_ICON_SIZE_POINTS: f32 = 24.0;
+
+// Writes highlights and bookmark notes into the document as standard annotations,
+// so any pdf reader can show them
+pub fn write_annotations(pdf: &PdfDocument, highlights: &[Highlight], bookmarks: &[Bookmark]) -> Result<()> {
+    let pages = pdf.pages();
+    for highlight in highlights {
+        let mut page = pages.get(highlight.page_index as u16)?;
+        let bounds: Vec<PdfRect> = highlight.rects.iter().map(|rect| from_page_rect(&page, rect)).collect();
+        let Some(all_bounds) = bounds.iter().copied().reduce(union) else {
+            continue;
+        };
+        let mut annotation = page.annotations_mut().create_highlight_annotation()?;
+        // Readers clip the highlight to its /Rect, which has to cover every line
+        annotation.set_bounds(all_bounds)?;
+        annotation.set_stroke_color(to_pdf_color(highlight.color))?;
+        for line_bounds in &bounds {
+            annotation
+                .attachment_points_mut()
+                .create_attachment_point_at_end(PdfQuadPoints::from_rect(line_bounds))?;
+        }
+        if !highlight.note.is_empty() {
+            annotation.set_contents(&highlight.note)?;
+        }
+    }
+    for bookmark in bookmarks.iter().filter(|bookmark| !bookmark.note.is_empty()) {
+        let mut page = pages.get(bookmark.page_index as u16)?;
+        let top = page.height();
+        let mut annotation = page.annotations_mut().create_text_annotation(&bookmark.note)?;
+        // Pinned to the top left corner of the page, where it won't cover the text
+        annotation.set_position(PdfPoints::ZERO, top - PdfPoints::new(NOTE_ICON_SIZE_POINTS))?;
+        annotation.set_width(PdfPoints::new(NOTE_ICON_SIZE_POINTS))?;
+        annotation.set_height(PdfPoints::new(NOTE_ICON_SIZE_POINTS))?;
+    }
+    Ok(())
+}
+
+// Inverse of pdf_text::to_page_rect
+fn from_page_rect(page: &PdfPage, rect: &PageRect) -> PdfRect {
+    let corners = [(rect.left, rect.bottom), (rect.right, rect.top)]
+        .map(|(x, y)| fraction_to_points(page, x, y, PdfPageRenderRotation::None).unwrap_or((0.0, 0.0)));
+    let [(first_x, first_y), (second_x, second_y)] = corners;
+    PdfRect::new_from_values(first_y.min(second_y), first_x.min(second_x), first_y.max(second_y), first_x.max(second_x))
+}
+
+fn union(first: PdfRect, second: PdfRect) -> PdfRect {
+    PdfRect::new_from_values(
+        first.bottom().value.min(second.bottom().value),
+        first.left().value.min(second.left().value),
+        first.top().value.max(second.top().value),
+        first.right().value.max(second.right().value),
+    )
+}
+
+fn to_pdf_color(argb: u32) -> PdfColor {
+    PdfColor::new((argb >> 16) as u8, (argb >> 8) as u8, argb as u8, (argb >> 24) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{fixture_path, with_pdfium};
+
+    #[test]
+    #[ignore = "needs pdfium, run with --ignored"]
+    fn highlights_are_bounded_by_all_their_lines() {
+        with_pdfium(|pdfium| {
+            let pdf = pdfium.load_pdf_from_file(&fixture_path("portrait"), None).unwrap();
+            let highlight = Highlight {
+                highlight_id: "highlight".to_string(),
+                page_index: 0,
+                first_char_index: 0,
+                char_count: 0,
+                color: 0xFFFFFF00,
+                note: String::new(),
+                quoted_text: String::new(),
+                rects: vec![
+                    PageRect { left: 0.1, top: 0.1, right: 0.9, bottom: 0.15 },
+                    PageRect { left: 0.1, top: 0.15, right: 0.5, bottom: 0.2 },
+                ],
+            };
+            write_annotations(&pdf, &[highlight], &[]).unwrap();
+            let page = pdf.pages().get(0).unwrap();
+            let (width, height) = (page.width().value, page.height().value);
+            let bounds = page.annotations().get(0).unwrap().bounds().unwrap();
+            let expected = [0.1 * width, 0.8 * height, 0.9 * width, 0.9 * height];
+            let actual = [bounds.left().value, bounds.bottom().value, bounds.right().value, bounds.top().value];
+            for (actual, expected) in actual.into_iter().zip(expected) {
+                assert!((actual - expected).abs() < 0.5, "{actual} != {expected}");
+            }
+        });
+    }
+}
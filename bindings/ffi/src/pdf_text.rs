@@ -0,0 +1,268 @@
+use anyhow::{Context, Result};
+use pdfium_render::prelude::*;
+use crate::domain::{PageRect, PageSelection, SearchResult};
+
+const SNIPPET_CONTEXT_CHARS: usize = 30;
+// How far from a glyph, in pdf points, a selection handle may land and still pick it
+const SELECTION_TOLERANCE_POINTS: f32 = 8.0;
+// Fractions of the page are mapped to pdf points through a virtual device this many pixels on each side,
+// fine enough for sub-point precision on any page
+const DEVICE_SIZE: i32 = 1 << 20;
+
+pub struct PageChar {
+    pub character: char,
+    pub bounds: PdfRect,
+}
+
+pub fn get_page_chars(page: &PdfPage) -> Result<Vec<PageChar>> {
+    let text = page.text()?;
+    let chars = text.chars();
+    let page_chars = chars
+        .iter()
+        .map(|text_char| PageChar {
+            character: text_char.unicode_char().unwrap_or(char::REPLACEMENT_CHARACTER),
+            bounds: text_char.loose_bounds().unwrap_or(PdfRect::ZERO),
+        })
+        .collect();
+    Ok(page_chars)
+}
+
+pub fn search_page(page: &PdfPage, page_index: i32, query: &str) -> Result<Vec<SearchResult>> {
+    let query: Vec<char> = query.chars().map(fold_case).collect();
+    if query.is_empty() {
+        return Ok(vec![]);
+    }
+    let page_chars = get_page_chars(page)?;
+    let folded_chars: Vec<char> = page_chars.iter().map(|page_char| fold_case(page_char.character)).collect();
+    let mut results = vec![];
+    let mut start = 0;
+    while start + query.len() <= folded_chars.len() {
+        if folded_chars[start..start + query.len()] != query[..] {
+            start += 1;
+            continue;
+        }
+        let end = start + query.len();
+        let bounds: Vec<PdfRect> = page_chars[start..end].iter().map(|page_char| page_char.bounds).collect();
+        results.push(SearchResult {
+            page_index,
+            snippet: get_snippet(&page_chars, start, end),
+            rects: merge_line_rects(page, &bounds),
+        });
+        start = end;
+    }
+    Ok(results)
+}
+
+pub struct TextSelection {
+    pub first_char_index: i32,
+    pub char_count: i32,
+    pub text: String,
+    pub rects: Vec<PageRect>,
+}
+
+// Resolves a selection made on the page displayed at any size, turned by rotation, into the characters it spans
+pub fn select_text(page: &PdfPage, selection: &PageSelection, rotation: PdfPageRenderRotation) -> Result<TextSelection> {
+    let text = page.text()?;
+    let chars = text.chars();
+    let start = char_index_at_view_point(page, &chars, selection, rotation, selection.start_x, selection.start_y)?;
+    let end = char_index_at_view_point(page, &chars, selection, rotation, selection.end_x, selection.end_y)?;
+    let (first, last) = (start.min(end), start.max(end));
+    let char_count = last - first + 1;
+    let selected_text = (first..=last)
+        .filter_map(|index| chars.get(index).ok()?.unicode_char())
+        .collect();
+    let bounds: Vec<PdfRect> = text
+        .segments_subset(first, char_count)
+        .iter()
+        .map(|segment| segment.bounds())
+        .collect();
+    Ok(TextSelection {
+        first_char_index: first as i32,
+        char_count: char_count as i32,
+        text: selected_text,
+        rects: merge_line_rects(page, &bounds),
+    })
+}
+
+fn char_index_at_view_point(
+    page: &PdfPage,
+    chars: &PdfPageTextChars,
+    selection: &PageSelection,
+    rotation: PdfPageRenderRotation,
+    view_x: f32,
+    view_y: f32,
+) -> Result<usize> {
+    let (x, y) = fraction_to_points(page, view_x / selection.view_width, view_y / selection.view_height, rotation)?;
+    let tolerance = PdfPoints::new(SELECTION_TOLERANCE_POINTS);
+    let text_char = chars
+        .get_char_near_point(PdfPoints::new(x), tolerance, PdfPoints::new(y), tolerance)
+        .context(format!("No text at {view_x}x{view_y}"))?;
+    Ok(text_char.index())
+}
+
+pub fn get_snippet(page_chars: &[PageChar], start: usize, end: usize) -> String {
+    let snippet_start = start.saturating_sub(SNIPPET_CONTEXT_CHARS);
+    let snippet_end = (end + SNIPPET_CONTEXT_CHARS).min(page_chars.len());
+    page_chars[snippet_start..snippet_end]
+        .iter()
+        .map(|page_char| if page_char.character.is_whitespace() { ' ' } else { page_char.character })
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+// Joins the boxes of consecutive characters sitting on the same line into a single rect.
+// Lines are told apart in pdf points, which are upright for any /Rotate the page displays with.
+pub fn merge_line_rects(page: &PdfPage, bounds: &[PdfRect]) -> Vec<PageRect> {
+    let mut lines: Vec<PdfRect> = vec![];
+    for char_bounds in bounds.iter().filter(|char_bounds| **char_bounds != PdfRect::ZERO) {
+        match lines.last_mut() {
+            Some(line) if is_same_line(line, char_bounds) => {
+                *line = PdfRect::new_from_values(
+                    line.bottom().value.min(char_bounds.bottom().value),
+                    line.left().value.min(char_bounds.left().value),
+                    line.top().value.max(char_bounds.top().value),
+                    line.right().value.max(char_bounds.right().value),
+                );
+            }
+            _ => lines.push(*char_bounds),
+        }
+    }
+    lines.iter().map(|line| to_page_rect(page, line)).collect()
+}
+
+// Converts pdf points into fractions of the page as the pdf displays it - with its own /Rotate applied,
+// without the rotation of the page layout. Readers turn them along with the page.
+pub fn to_page_rect(page: &PdfPage, bounds: &PdfRect) -> PageRect {
+    let corners = [(bounds.left(), bounds.bottom()), (bounds.right(), bounds.top())]
+        .map(|(x, y)| points_to_fraction(page, x.value, y.value, PdfPageRenderRotation::None).unwrap_or((0.0, 0.0)));
+    let [(first_x, first_y), (second_x, second_y)] = corners;
+    PageRect {
+        left: first_x.min(second_x),
+        top: first_y.min(second_y),
+        right: first_x.max(second_x),
+        bottom: first_y.max(second_y),
+    }
+}
+
+// Pdf points at fractions x, y of the page displayed turned by rotation, on top of its own /Rotate.
+// Fractions have their origin in the top left corner of what's displayed.
+pub fn fraction_to_points(page: &PdfPage, x: f32, y: f32, rotation: PdfPageRenderRotation) -> Result<(f32, f32)> {
+    let device_x = (x * DEVICE_SIZE as f32).round() as Pixels;
+    let device_y = (y * DEVICE_SIZE as f32).round() as Pixels;
+    let (x, y) = page.pixels_to_points(device_x, device_y, &device_config(rotation))?;
+    Ok((x.value, y.value))
+}
+
+// Inverse of fraction_to_points
+pub fn points_to_fraction(page: &PdfPage, x: f32, y: f32, rotation: PdfPageRenderRotation) -> Result<(f32, f32)> {
+    let (device_x, device_y) = page.points_to_pixels(PdfPoints::new(x), PdfPoints::new(y), &device_config(rotation))?;
+    Ok((device_x as f32 / DEVICE_SIZE as f32, device_y as f32 / DEVICE_SIZE as f32))
+}
+
+fn device_config(rotation: PdfPageRenderRotation) -> PdfRenderConfig {
+    PdfRenderConfig::new().set_fixed_size(DEVICE_SIZE, DEVICE_SIZE).rotate(rotation, false)
+}
+
+fn is_same_line(line: &PdfRect, char_bounds: &PdfRect) -> bool {
+    let overlap = line.top().value.min(char_bounds.top().value) - line.bottom().value.max(char_bounds.bottom().value);
+    let char_height = char_bounds.top().value - char_bounds.bottom().value;
+    overlap > char_height / 2.0
+}
+
+fn fold_case(character: char) -> char {
+    character.to_lowercase().next().unwrap_or(character)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{fixture_path, with_pdfium};
+
+    const ROTATIONS: [PdfPageRenderRotation; 4] = [
+        PdfPageRenderRotation::None,
+        PdfPageRenderRotation::Degrees90,
+        PdfPageRenderRotation::Degrees180,
+        PdfPageRenderRotation::Degrees270,
+    ];
+
+    // Where fractions x, y of the page as the pdf displays it end up once it's turned clockwise by rotation
+    fn rotate_fraction(x: f32, y: f32, rotation: PdfPageRenderRotation) -> (f32, f32) {
+        match rotation {
+            PdfPageRenderRotation::None => (x, y),
+            PdfPageRenderRotation::Degrees90 => (1.0 - y, x),
+            PdfPageRenderRotation::Degrees180 => (1.0 - x, 1.0 - y),
+            PdfPageRenderRotation::Degrees270 => (y, 1.0 - x),
+        }
+    }
+
+    fn first_rect(page: &PdfPage, query: &str) -> PageRect {
+        let results = search_page(page, 0, query).unwrap();
+        results[0].rects[0]
+    }
+
+    #[test]
+    #[ignore = "needs pdfium, run with --ignored"]
+    fn rects_are_relative_to_the_page_as_the_pdf_displays_it() {
+        with_pdfium(|pdfium| {
+            // "Hello fixture" starts at 72x72 points, from the bottom left corner of the 612x792 points page
+            let upright = pdfium.load_pdf_from_file(&fixture_path("portrait"), None).unwrap();
+            let rect = first_rect(&upright.pages().get(0).unwrap(), "Hello");
+            assert!((rect.left - 72.0 / 612.0).abs() < 0.01, "{rect:?}");
+            assert!(rect.top < 720.0 / 792.0 && rect.bottom > 710.0 / 792.0, "{rect:?}");
+            assert!(rect.right - rect.left > rect.bottom - rect.top, "{rect:?}");
+            // Turned clockwise by /Rotate 90 the text runs down from the top left corner of a 792x612 points page
+            let rotated = pdfium.load_pdf_from_file(&fixture_path("rotated"), None).unwrap();
+            let rect = first_rect(&rotated.pages().get(0).unwrap(), "Hello");
+            assert!((rect.top - 72.0 / 612.0).abs() < 0.01, "{rect:?}");
+            assert!(rect.left < 72.0 / 792.0 && rect.right > 82.0 / 792.0, "{rect:?}");
+            assert!(rect.bottom - rect.top > rect.right - rect.left, "{rect:?}");
+        });
+    }
+
+    #[test]
+    #[ignore = "needs pdfium, run with --ignored"]
+    fn selection_picks_the_displayed_text_for_any_rotation() {
+        with_pdfium(|pdfium| {
+            for fixture in ["portrait", "rotated"] {
+                let pdf = pdfium.load_pdf_from_file(&fixture_path(fixture), None).unwrap();
+                let page = pdf.pages().get(0).unwrap();
+                let rect = first_rect(&page, "Hello");
+                let (center_x, center_y) = ((rect.left + rect.right) / 2.0, (rect.top + rect.bottom) / 2.0);
+                for rotation in ROTATIONS {
+                    let (view_x, view_y) = rotate_fraction(center_x, center_y, rotation);
+                    let selection = PageSelection {
+                        view_width: 1000.0,
+                        view_height: 1000.0,
+                        start_x: view_x * 1000.0,
+                        start_y: view_y * 1000.0,
+                        end_x: view_x * 1000.0,
+                        end_y: view_y * 1000.0,
+                    };
+                    let text_selection = select_text(&page, &selection, rotation).unwrap();
+                    assert!(
+                        !text_selection.text.is_empty() && "Hello".contains(&text_selection.text),
+                        "{fixture} {rotation:?} selected {:?}",
+                        text_selection.text,
+                    );
+                }
+            }
+        });
+    }
+
+    #[test]
+    #[ignore = "needs pdfium, run with --ignored"]
+    fn fractions_convert_back_to_the_same_points() {
+        with_pdfium(|pdfium| {
+            for fixture in ["landscape", "rotated"] {
+                let pdf = pdfium.load_pdf_from_file(&fixture_path(fixture), None).unwrap();
+                let page = pdf.pages().get(0).unwrap();
+                for rotation in ROTATIONS {
+                    let (x, y) = points_to_fraction(&page, 100.0, 200.0, rotation).unwrap();
+                    let (points_x, points_y) = fraction_to_points(&page, x, y, rotation).unwrap();
+                    assert!((points_x - 100.0).abs() < 0.1 && (points_y - 200.0).abs() < 0.1, "{fixture} {rotation:?}");
+                }
+            }
+        });
+    }
+}
@@ -1,43 +1,127 @@
-use std::cmp::{max, min};
-use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread;
 use std::thread::JoinHandle;
 use anyhow::{Context, Result};
-use pdfium_render::metadata::PdfDocumentMetadataTagType;
 use pdfium_render::prelude::*;
 use crate::global_state::GlobalResult;
 
 use uuid::Uuid;
-use crate::domain::{Bitmap, Page};
+use crate::domain::{Bitmap, Bookmark, EngineStatus, FitMode, Highlight, ImageEncoding, OutlineEntry, Page, PageRect, PageRotation, PageSelection, PdfLoadingError, PixelFormat, RenderQuality};
+use crate::page_cache::PageCache;
+use crate::render_disk_cache::{document_key, RenderDiskCache, RenderKey};
+use crate::page_layout::{PageLayout, RenderSize};
+use crate::page_tiles::{load_tiles, TileCache};
+use crate::render_scheduler::RenderScheduler;
+use crate::pdf_annotations::write_annotations;
+use crate::pdf_text::{search_page, select_text};
 
 pub fn generate_pdf_uuid() -> String {
     Uuid::new_v4().to_string()
 }
 
+const PDF_MIME_TYPE: &str = "application/pdf";
+// Used until the ui reports its viewport
+pub const DEFAULT_PAGE_LAYOUT: PageLayout = PageLayout {
+    box_width: 1000,
+    box_height: 1600,
+    fit_mode: FitMode::Width,
+    rotation: PageRotation::None,
+};
+// Library grid cells are a fraction of the screen width, so thumbnails don't need more
+const THUMBNAIL_LAYOUT: PageLayout = PageLayout {
+    box_width: 360,
+    box_height: 480,
+    fit_mode: FitMode::WholePage,
+    rotation: PageRotation::None,
+};
+
 pub struct PdfiumManager {
     pub pdfium_action_sender: Mutex<Sender<PdfiumAction>>,
+    #[allow(dead_code)]
     pub pdfium_thread_handle: JoinHandle<()>,
 }
 
 impl PdfiumManager {
-    pub fn new(global_action_sender: Arc<Mutex<Sender<GlobalResult>>>) -> PdfiumManager {
+    pub fn new(
+        global_action_sender: Arc<Mutex<Sender<GlobalResult>>>,
+        page_cache_bytes: u64,
+        pixel_format: PixelFormat,
+        render_cache_directory: PathBuf,
+        library_search_paths: Vec<PathBuf>,
+    ) -> PdfiumManager {
         let (action_sender, action_receiver): (Sender<PdfiumAction>, Receiver<PdfiumAction>) = channel();
         let pdfium_thread_handle = thread::spawn(move || {
-            let pdfium_bindings = Pdfium::bind_to_library(Pdfium::pdfium_platform_library_name_at_path("./"))
-                .or_else(|_| Pdfium::bind_to_system_library())
-                .unwrap();
-            let pdfium = Pdfium::new(pdfium_bindings);
-            let mut current_pdfium_document: Option<PdfDocument> = None;
-            let mut current_document_pages: HashMap<i32, Arc<Bitmap>> = HashMap::new();
+            let pdfium_bindings = match bind_pdfium(&library_search_paths) {
+                Ok(pdfium_bindings) => pdfium_bindings,
+                Err(reason) => {
+                    error!("PdfiumManager - binding pdfium failed - {reason}");
+                    send_result(&global_action_sender, GlobalResult::EngineStatusChanged {
+                        status: EngineStatus::Unavailable { reason },
+                    });
+                    return reject_actions(action_receiver, &global_action_sender);
+                }
+            };
+            send_result(&global_action_sender, GlobalResult::EngineStatusChanged { status: EngineStatus::Ready });
+            let pdfium = &Pdfium::new(pdfium_bindings);
+            let mut current_pdfium_document: Option<OpenDocument> = None;
+            let mut page_cache = PageCache::new(page_cache_bytes);
+            let mut current_search: Option<SearchJob> = None;
+            let mut index_jobs: VecDeque<IndexJob> = VecDeque::new();
+            let mut page_layout = DEFAULT_PAGE_LAYOUT;
+            let mut tile_cache = TileCache::default();
+            let mut render_scheduler = RenderScheduler::default();
+            let mut render_disk_cache = RenderDiskCache::new(render_cache_directory, pixel_format);
             loop {
-                let action = action_receiver.recv().unwrap();
+                // With background work pending only peek at the queue, so the queued actions are all taken in
+                // (and page requests coalesced) before the next page gets rendered, searched or indexed
+                let has_background_work = !render_scheduler.is_empty() || current_search.is_some() || !index_jobs.is_empty();
+                let action = if !has_background_work {
+                    match action_receiver.recv() {
+                        Ok(action) => action,
+                        // The store is gone
+                        Err(_) => return,
+                    }
+                } else {
+                    match action_receiver.try_recv() {
+                        Ok(action) => action,
+                        Err(_) => {
+                            if !render_scheduler.is_empty() {
+                                match current_pdfium_document.as_ref() {
+                                    Some(pdf) => render_next_page(
+                                        pdf,
+                                        &mut render_scheduler,
+                                        &page_layout,
+                                        pixel_format,
+                                        &mut page_cache,
+                                        &mut render_disk_cache,
+                                        &global_action_sender,
+                                    ),
+                                    None => render_scheduler.clear(),
+                                }
+                            } else if let Some(search) = current_search.take() {
+                                current_search = continue_search(search, current_pdfium_document.as_deref(), &global_action_sender);
+                            } else if let Some(index_job) = index_jobs.pop_front() {
+                                if let Some(index_job) = continue_indexing(pdfium, index_job, &global_action_sender) {
+                                    index_jobs.push_front(index_job);
+                                }
+                            }
+                            continue;
+                        }
+                    }
+                };
                 match action {
-                    PdfiumAction::LoadPdf { uuid, file_name, bytes } => {
-                        let result = pdfium.load_pdf_from_byte_slice(Box::leak(Box::new(bytes)), None);
-                        match result {
+                    PdfiumAction::LoadPdf { uuid, file_name, path, password } => {
+                        current_search = None;
+                        let has_password = password.is_some();
+                        match OpenDocument::load(pdfium, &path, password) {
                             Ok(pdf) => {
+                                render_disk_cache.set_document_key(get_document_key(&uuid, &path));
                                 let metadata = pdf.metadata();
                                 let title = metadata
                                     .get(PdfDocumentMetadataTagType::Title)
@@ -53,80 +137,179 @@ impl PdfiumManager {
                                     title
                                 };
                                 let page_count: i32 = pdf.pages().len().into();
-                                let thumbnail = get_thumbnail(&pdf).ok();
-                                global_action_sender
-                                    .lock()
-                                    .unwrap()
-                                    .send(GlobalResult::PdfLoaded {
-                                        id: uuid.clone(),
-                                        title: display_title,
-                                        author,
-                                        thumbnail,
-                                        page_count,
-                                    })
-                                    .unwrap();
+                                let thumbnail = get_thumbnail(&pdf, pixel_format, &mut render_disk_cache).ok();
+                                let outline = get_outline(&pdf);
+                                send_result(&global_action_sender, GlobalResult::PdfLoaded {
+                                    id: uuid.clone(),
+                                    title: display_title,
+                                    author,
+                                    thumbnail,
+                                    page_count,
+                                    outline,
+                                });
+                                // Replacing the previous document closes it
                                 current_pdfium_document = Some(pdf);
-                                current_document_pages.clear();
+                                page_cache.clear();
+                                tile_cache.clear();
+                                render_scheduler.clear();
+                            }
+                            Err(error) if is_password_error(&error) => {
+                                send_result(&global_action_sender, GlobalResult::PasswordRequired {
+                                    uuid,
+                                    file_name: Some(file_name),
+                                    wrong_password: has_password,
+                                });
                             }
                             Err(error) => {
                                 error!("Loading pdf failed: {error}");
-                                global_action_sender
-                                    .lock()
-                                    .unwrap()
-                                    .send(GlobalResult::PdfLoadingFailed { uuid: uuid.clone() })
-                                    .unwrap();
+                                send_result(&global_action_sender, GlobalResult::PdfLoadingFailed {
+                                    uuid: uuid.clone(),
+                                    error: pdfium_loading_error(&error),
+                                });
                             }
                         }
                     }
-                    PdfiumAction::PageLoadRequested { page_index: index } => {
-                        info!("PdfiumAction::PageLoadRequested");
-                        current_pdfium_document = match current_pdfium_document.take() {
-                            None => None,
-                            Some(pdf) => {
-                                let pages = pdf.pages();
-                                let pages_count = pages.len() as i32;
-                                let page_indices_to_load: Vec<i32> = (max(0, index - 5)..min(index + 5, pages_count))
-                                    .filter(|key| !current_document_pages.contains_key(key))
-                                    .collect();
-                                let mut rendered_pages: Vec<Arc<Page>> = vec![];
-                                for page_index_to_load in page_indices_to_load {
-                                    let page_index = page_index_to_load as u16;
-                                    info!("PdfiumAction::PageLoadRequested - pages size - {pages_count} - requested index - {page_index_to_load}");
-                                    let page_result = pages.get(page_index);
-                                    match page_result {
-                                        Ok(page) => {
-                                            let image = get_page_image(page, 1000);
-                                            match image {
-                                                Ok(page_image) => {
-                                                    rendered_pages.push( Arc::new(Page { index: page_index_to_load, image: Some(page_image) } ));
-                                                }
-                                                Err(error) => {
-                                                    error!("PdfiumAction::PageLoadRequested - error rendering page - {error}")
-                                                }
-                                            }
-                                        }
-                                        Err(error) => {
-                                            error!("PdfiumAction::PageLoadRequested - error loading page - {error}")
-                                        }
-                                    }
-                                }
-                                for page in &rendered_pages {
-                                    let image = page.image();
-                                    if let Some(image) = image {
-                                        current_document_pages.insert(page.index, Arc::clone(&image));
-                                    };
-                                }
-                                global_action_sender
-                                    .lock()
-                                    .unwrap()
-                                    .send(GlobalResult::PagesLoaded {
-                                        pages: rendered_pages,
-                                    })
-                                    .unwrap();
-                                Some(pdf)
+                    PdfiumAction::OpenPdf { uuid, path, password } => {
+                        current_search = None;
+                        let has_password = password.is_some();
+                        match OpenDocument::load(pdfium, &path, password) {
+                            Ok(pdf) => {
+                                render_disk_cache.set_document_key(get_document_key(&uuid, &path));
+                                let page_count: i32 = pdf.pages().len().into();
+                                let thumbnail = get_thumbnail(&pdf, pixel_format, &mut render_disk_cache).ok();
+                                let outline = get_outline(&pdf);
+                                send_result(&global_action_sender, GlobalResult::BookOpened {
+                                    uuid,
+                                    thumbnail,
+                                    page_count,
+                                    outline,
+                                });
+                                current_pdfium_document = Some(pdf);
+                                page_cache.clear();
+                                tile_cache.clear();
+                                render_scheduler.clear();
+                            }
+                            Err(error) if is_password_error(&error) => {
+                                send_result(&global_action_sender, GlobalResult::PasswordRequired {
+                                    uuid,
+                                    file_name: None,
+                                    wrong_password: has_password,
+                                });
+                            }
+                            Err(error) => {
+                                error!("Opening pdf {uuid} from {} failed: {error}", path.display());
+                                send_result(&global_action_sender, GlobalResult::PdfLoadingFailed { uuid, error: pdfium_loading_error(&error) });
+                            }
+                        }
+                    }
+                    PdfiumAction::ClosePdf => {
+                        current_search = None;
+                        current_pdfium_document = None;
+                        render_disk_cache.set_document_key(None);
+                        page_cache.clear();
+                        tile_cache.clear();
+                        render_scheduler.clear();
+                    }
+                    PdfiumAction::Search { search_id, query } => {
+                        current_search = Some(SearchJob { search_id, query, next_page_index: 0 });
+                    }
+                    PdfiumAction::CancelSearch => {
+                        current_search = None;
+                    }
+                    PdfiumAction::CreateHighlight { uuid, highlight_id, page_index, selection, color, note } => {
+                        let text_selection = current_pdfium_document
+                            .as_ref()
+                            .context("No open document")
+                            .and_then(|pdf| select_text(&pdf.pages().get(page_index as u16)?, &selection, page_layout.pdfium_rotation()));
+                        match text_selection {
+                            Ok(text_selection) => {
+                                let highlight = Highlight {
+                                    highlight_id,
+                                    page_index,
+                                    first_char_index: text_selection.first_char_index,
+                                    char_count: text_selection.char_count,
+                                    color,
+                                    note,
+                                    quoted_text: text_selection.text,
+                                    rects: text_selection.rects,
+                                };
+                                send_result(&global_action_sender, GlobalResult::HighlightCreated { uuid, highlight });
+                            }
+                            Err(error) => {
+                                error!("PdfiumAction::CreateHighlight - error selecting text - {error}");
+                                send_result(&global_action_sender, GlobalResult::HighlightFailed { page_index });
+                            }
+                        }
+                    }
+                    PdfiumAction::ExportAnnotatedPdf { uuid, file_name, path, password, highlights, bookmarks } => {
+                        // Works on a fresh copy of the source so the open document stays untouched
+                        let bytes = OpenDocument::load(pdfium, &path, password)
+                            .map_err(anyhow::Error::from)
+                            .and_then(|pdf| {
+                                write_annotations(&pdf, &highlights, &bookmarks)?;
+                                Ok(pdf.save_to_bytes()?)
+                            });
+                        match bytes {
+                            Ok(bytes) => {
+                                send_result(&global_action_sender, GlobalResult::FileExported {
+                                    file_name,
+                                    mime_type: PDF_MIME_TYPE.to_string(),
+                                    bytes,
+                                });
+                            }
+                            Err(error) => {
+                                error!("PdfiumAction::ExportAnnotatedPdf - error writing annotations - {error}");
+                                send_result(&global_action_sender, GlobalResult::ExportFailed { uuid });
+                            }
+                        }
+                    }
+                    PdfiumAction::IndexBook { uuid, path, password } => {
+                        index_jobs.push_back(IndexJob { uuid, path, password, document: None, page_texts: vec![] });
+                    }
+                    PdfiumAction::SetPageLayout { page_layout: new_page_layout, page_index } => {
+                        // Only a bigger box leaves the pages rendered so far usable
+                        if new_page_layout.fit_mode != page_layout.fit_mode || new_page_layout.rotation != page_layout.rotation {
+                            page_cache.clear();
+                        }
+                        page_layout = new_page_layout;
+                        tile_cache.clear();
+                        // Pages rendered so far stay on screen as placeholders until their sharper versions are ready
+                        if let Some(pdf) = current_pdfium_document.as_ref() {
+                            render_scheduler.request(page_index, pdf.pages().len().into());
+                        }
+                    }
+                    PdfiumAction::ClearCache => {
+                        render_disk_cache.clear();
+                    }
+                    PdfiumAction::LoadTiles { page_index, zoom_level, region } => {
+                        let tiles = if zoom_level <= 0 {
+                            // The page image is sharp enough without zoom
+                            Ok(vec![])
+                        } else {
+                            current_pdfium_document
+                                .as_ref()
+                                .context("No open document")
+                                .and_then(|pdf| {
+                                    let page = pdf.pages().get(page_index as u16)?;
+                                    load_tiles(&page, page_index, zoom_level, &region, &page_layout, pixel_format, &mut tile_cache)
+                                })
+                        };
+                        match tiles {
+                            Ok(tiles) => {
+                                send_result(&global_action_sender, GlobalResult::TilesLoaded { page_index, tiles });
+                            }
+                            Err(error) => {
+                                error!("PdfiumAction::LoadTiles - error rendering tiles - {error}");
+                                send_result(&global_action_sender, GlobalResult::RenderingFailed { page_index });
                             }
                         }
                     }
+                    PdfiumAction::PageLoadRequested { page_index } => {
+                        info!("PdfiumAction::PageLoadRequested - requested index - {page_index}");
+                        if let Some(pdf) = current_pdfium_document.as_ref() {
+                            render_scheduler.request(page_index, pdf.pages().len().into());
+                        }
+                    }
                 }
             }
         });
@@ -137,34 +320,467 @@ impl PdfiumManager {
     }
 }
 
-fn get_thumbnail(pdf: &PdfDocument) -> Result<Arc<Bitmap>> {
-    let first_page = pdf.pages().get(0)?;
-    get_page_image(first_page, 1000)
-}
-
-fn get_page_image(page: PdfPage, max_width: u16) -> Result<Arc<Bitmap>> {
-    let width = max_width;
-    let page_ratio = (page.height().value / page.width().value) as u16;
-    let height = max_width * page_ratio;
-    let pdf_bitmap = page.render(width, height, None)?;
-    let pdf_bitmap: Vec<u32> = pdf_bitmap
-        .as_bytes()
-        .chunks(4)
-        .map(|pixel| {
-            let a = u32::from(pixel[3]) << 24;
-            let r = u32::from(pixel[0]) << 16;
-            let g = u32::from(pixel[1]) << 8;
-            let b = u32::from(pixel[2]);
-            let argb: u32 = a | r | g | b;
-            argb
-        })
+// Renders the pending page closest to the focus and publishes it, or loads its render stored on disk. A page
+// with nothing to show yet gets a preview first.
+// Pages aren't rendered on a pool of workers: pdfium isn't reentrant and only one instance of it can be bound
+// per process (pdfium-render's thread_safe feature just serializes every call behind a lock), so extra
+// document handles on other threads would still render one page at a time. Pages are rendered one by one on
+// this thread in the order the scheduler hands them out - the focused page first, then its neighbours nearest
+// first - and each is published in its own PagesLoaded batch in that same order.
+fn render_next_page(
+    pdf: &PdfDocument,
+    render_scheduler: &mut RenderScheduler,
+    page_layout: &PageLayout,
+    pixel_format: PixelFormat,
+    page_cache: &mut PageCache,
+    render_disk_cache: &mut RenderDiskCache,
+    global_action_sender: &Arc<Mutex<Sender<GlobalResult>>>,
+) {
+    let focus_index = render_scheduler.focus_index();
+    let key = loop {
+        let Some(page_index) = render_scheduler.next() else {
+            return;
+        };
+        if let Some(size) = missing_render_size(pdf, page_index, page_layout, page_cache) {
+            break RenderKey { page_index, size, rotation: page_layout.rotation };
+        }
+    };
+    let page_index = key.page_index;
+    let stored_image = render_disk_cache.get(&key);
+    let is_stored = stored_image.is_some();
+    let image = match stored_image {
+        Some(stored_image) => stored_image,
+        None => {
+            if page_cache.get(page_index).is_none() {
+                match render_image(pdf, page_index, &page_layout.preview(), pixel_format) {
+                    // Previews stay out of the page cache, the full render replaces it right away
+                    Ok(preview) => send_result(global_action_sender, GlobalResult::PagesLoaded {
+                        pages: vec![Arc::new(Page { index: page_index, image: Some(preview), quality: RenderQuality::Preview, highlights: vec![], tiles: vec![] })],
+                    }),
+                    Err(error) => { error!("PdfiumAction::PageLoadRequested - error rendering preview of page {page_index} - {error}") }
+                }
+            }
+            match render_image(pdf, page_index, page_layout, pixel_format) {
+                Ok(image) => image,
+                Err(error) => {
+                    error!("PdfiumAction::PageLoadRequested - error rendering page {page_index} - {error}");
+                    send_result(global_action_sender, GlobalResult::RenderingFailed { page_index });
+                    return;
+                }
+            }
+        }
+    };
+    let changes = page_cache.insert(page_index, image.clone(), focus_index);
+    // Other pages whose images the cache compressed or dropped to make room
+    let mut changed_page_indices: Vec<i32> = changes
+        .encoded
+        .into_iter()
+        .map(|(page_index, _)| page_index)
+        .chain(changes.evicted)
+        .filter(|changed_page_index| *changed_page_index != page_index)
         .collect();
-    let bitmap_uid = Uuid::new_v4().to_string();
-    Ok(Bitmap::new(width.into(), height.into(), bitmap_uid, pdf_bitmap))
+    changed_page_indices.sort();
+    changed_page_indices.dedup();
+    // What ends up in the cache is what gets published - a rendered page that didn't fit the budget at all
+    // keeps its preview
+    let mut loaded_pages: Vec<Arc<Page>> = vec![];
+    let mut evicted_page_indices: Vec<i32> = vec![];
+    for changed_page_index in std::iter::once(&page_index).chain(&changed_page_indices) {
+        match page_cache.peek(*changed_page_index) {
+            Some(image) => loaded_pages.push(Arc::new(Page { index: *changed_page_index, image: Some(image), quality: RenderQuality::Full, highlights: vec![], tiles: vec![] })),
+            None if *changed_page_index != page_index => evicted_page_indices.push(*changed_page_index),
+            None => {}
+        }
+    }
+    if !loaded_pages.is_empty() {
+        send_result(global_action_sender, GlobalResult::PagesLoaded { pages: loaded_pages });
+    }
+    if !evicted_page_indices.is_empty() {
+        send_result(global_action_sender, GlobalResult::PagesEvicted { page_indices: evicted_page_indices });
+    }
+    if !is_stored {
+        render_disk_cache.insert(&key, image);
+    }
+}
+
+fn render_image(pdf: &PdfDocument, page_index: i32, page_layout: &PageLayout, pixel_format: PixelFormat) -> Result<Arc<Bitmap>> {
+    get_page_image(pdf.pages().get(page_index as u16)?, page_layout, pixel_format)
+}
+
+// The size to render the page at, None when it's already rendered at the page layout size or sharper
+fn missing_render_size(pdf: &PdfDocument, page_index: i32, page_layout: &PageLayout, page_cache: &mut PageCache) -> Option<RenderSize> {
+    match pdf.pages().get(page_index as u16) {
+        Ok(page) => {
+            let size = page_layout.fit(page.width().value, page.height().value);
+            let is_rendered = page_cache.get(page_index).map_or(false, |image| image.width >= size.width);
+            (!is_rendered).then_some(size)
+        }
+        Err(error) => {
+            error!("PdfiumAction::PageLoadRequested - error loading page {page_index} - {error}");
+            None
+        }
+    }
+}
+
+struct SearchJob {
+    search_id: String,
+    query: String,
+    next_page_index: i32,
+}
+
+// Searches a single page and returns the job to carry on with, or None once the whole document is done
+fn continue_search(
+    search: SearchJob,
+    pdf: Option<&PdfDocument>,
+    global_action_sender: &Arc<Mutex<Sender<GlobalResult>>>,
+) -> Option<SearchJob> {
+    let pages = pdf.map(|pdf| pdf.pages());
+    let pages_count = pages.as_ref().map_or(0, |pages| pages.len() as i32);
+    if search.next_page_index >= pages_count {
+        send_result(global_action_sender, GlobalResult::SearchFinished { search_id: search.search_id });
+        return None;
+    }
+    let page_index = search.next_page_index;
+    let results = pages
+        .as_ref()
+        .and_then(|pages| pages.get(page_index as u16).ok())
+        .map(|page| search_page(&page, page_index, &search.query));
+    match results {
+        Some(Ok(results)) if !results.is_empty() => {
+            send_result(
+                global_action_sender,
+                GlobalResult::SearchResultsFound { search_id: search.search_id.clone(), results },
+            );
+        }
+        Some(Err(error)) => { error!("PdfiumAction::Search - error searching page {page_index} - {error}") }
+        _ => {}
+    }
+    Some(SearchJob { next_page_index: page_index + 1, ..search })
+}
+
+// Looks for the library in each of library_search_paths, then wherever the system keeps its libraries
+pub fn bind_pdfium(library_search_paths: &[PathBuf]) -> std::result::Result<Box<dyn PdfiumLibraryBindings>, String> {
+    let mut failures = vec![];
+    for library_search_path in library_search_paths {
+        match Pdfium::bind_to_library(Pdfium::pdfium_platform_library_name_at_path(library_search_path)) {
+            Ok(pdfium_bindings) => return Ok(pdfium_bindings),
+            Err(error) => failures.push(format!("{}: {error}", library_search_path.display())),
+        }
+    }
+    match Pdfium::bind_to_system_library() {
+        Ok(pdfium_bindings) => Ok(pdfium_bindings),
+        Err(error) => {
+            failures.push(format!("system library: {error}"));
+            Err(failures.join(", "))
+        }
+    }
+}
+
+// Without pdfium every action the host waits on fails right away, so no book or page is left loading
+fn reject_actions(action_receiver: Receiver<PdfiumAction>, global_action_sender: &Arc<Mutex<Sender<GlobalResult>>>) {
+    for action in action_receiver {
+        match action {
+            PdfiumAction::LoadPdf { uuid, .. } | PdfiumAction::OpenPdf { uuid, .. } => {
+                send_result(global_action_sender, GlobalResult::PdfLoadingFailed { uuid, error: PdfLoadingError::EngineUnavailable });
+            }
+            PdfiumAction::Search { search_id, .. } => {
+                send_result(global_action_sender, GlobalResult::SearchFinished { search_id });
+            }
+            PdfiumAction::CreateHighlight { page_index, .. } => {
+                send_result(global_action_sender, GlobalResult::HighlightFailed { page_index });
+            }
+            PdfiumAction::ExportAnnotatedPdf { uuid, .. } => {
+                send_result(global_action_sender, GlobalResult::ExportFailed { uuid });
+            }
+            PdfiumAction::LoadTiles { page_index, .. } | PdfiumAction::PageLoadRequested { page_index } => {
+                send_result(global_action_sender, GlobalResult::RenderingFailed { page_index });
+            }
+            // Nothing waits on the rest
+            _ => { error!("PdfiumManager - pdfium is unavailable, dropping action") }
+        }
+    }
+}
+
+// The store outlives pdfium, a failed send only means the app is shutting down
+fn send_result(global_action_sender: &Arc<Mutex<Sender<GlobalResult>>>, result: GlobalResult) {
+    if global_action_sender.lock().unwrap().send(result).is_err() {
+        error!("PdfiumManager - the store stopped receiving results");
+    }
+}
+
+// A document kept together with the password it was opened with, as pdfium-render ties the password to the
+// lifetime of the document
+pub struct OpenDocument<'a> {
+    // Declared first so it's dropped before the password it borrows
+    document: PdfDocument<'a>,
+    _password: Option<String>,
+}
+
+impl<'a> OpenDocument<'a> {
+    pub fn load(pdfium: &'a Pdfium, path: &Path, password: Option<String>) -> Result<Self, PdfiumError> {
+        // Pdfium takes the password as a C string, one with a nul in it can't be right
+        if password.as_ref().map_or(false, |password| password.contains('\0')) {
+            return Err(PdfiumError::PdfiumLibraryInternalError(PdfiumInternalError::PasswordError));
+        }
+        // Safety: the password's buffer doesn't move along with the string, is never mutated and outlives the
+        // document, which is dropped first and never handed out by value
+        let borrowed_password: Option<&'a str> = password.as_deref().map(|password| unsafe { &*(password as *const str) });
+        let document = pdfium.load_pdf_from_file(path, borrowed_password)?;
+        Ok(OpenDocument { document, _password: password })
+    }
+}
+
+impl<'a> Deref for OpenDocument<'a> {
+    type Target = PdfDocument<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.document
+    }
+}
+
+// Pdfium can't be reached, so nothing can be loaded until it is
+#[derive(Debug)]
+pub struct EngineUnavailable;
+
+impl Display for EngineUnavailable {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Pdfium is unavailable")
+    }
+}
+
+impl std::error::Error for EngineUnavailable {}
+
+pub fn loading_error(error: &anyhow::Error) -> PdfLoadingError {
+    if error.downcast_ref::<EngineUnavailable>().is_some() {
+        return PdfLoadingError::EngineUnavailable;
+    }
+    if let Some(error) = error.downcast_ref::<PdfiumError>() {
+        return pdfium_loading_error(error);
+    }
+    match error.chain().find_map(|cause| cause.downcast_ref::<io::Error>()) {
+        Some(error) => io_loading_error(error),
+        None => PdfLoadingError::Io,
+    }
+}
+
+fn pdfium_loading_error(error: &PdfiumError) -> PdfLoadingError {
+    match error {
+        PdfiumError::IoError(error) => io_loading_error(error),
+        PdfiumError::PdfiumLibraryInternalError(PdfiumInternalError::FileError) => PdfLoadingError::Io,
+        PdfiumError::PdfiumLibraryInternalError(PdfiumInternalError::PasswordError) => PdfLoadingError::PasswordProtected,
+        // Security handlers pdfium doesn't implement
+        PdfiumError::PdfiumLibraryInternalError(PdfiumInternalError::SecurityError) => PdfLoadingError::UnsupportedFormat,
+        PdfiumError::LoadLibraryError(_) | PdfiumError::LoadLibraryFunctionNameError(_) => PdfLoadingError::EngineUnavailable,
+        _ => PdfLoadingError::CorruptFile,
+    }
+}
+
+fn io_loading_error(error: &io::Error) -> PdfLoadingError {
+    match error.kind() {
+        io::ErrorKind::OutOfMemory => PdfLoadingError::OutOfMemory,
+        _ => PdfLoadingError::Io,
+    }
+}
+
+fn is_password_error(error: &PdfiumError) -> bool {
+    matches!(error, PdfiumError::PdfiumLibraryInternalError(PdfiumInternalError::PasswordError))
+}
+
+struct IndexJob<'a> {
+    uuid: String,
+    path: PathBuf,
+    password: Option<String>,
+    // Opened lazily so queued jobs don't keep documents in memory
+    document: Option<OpenDocument<'a>>,
+    page_texts: Vec<String>,
 }
 
+// Extracts the text of a single page and returns the job to carry on with, or None once the book is done
+fn continue_indexing<'a>(
+    pdfium: &'a Pdfium,
+    mut index_job: IndexJob<'a>,
+    global_action_sender: &Arc<Mutex<Sender<GlobalResult>>>,
+) -> Option<IndexJob<'a>> {
+    if index_job.document.is_none() {
+        match OpenDocument::load(pdfium, &index_job.path, index_job.password.take()) {
+            Ok(document) => index_job.document = Some(document),
+            Err(error) => {
+                error!("PdfiumAction::IndexBook - opening {} failed - {error}", index_job.path.display());
+                return None;
+            }
+        }
+    }
+    let page_text = {
+        let pages = index_job.document.as_ref()?.pages();
+        let page_index = index_job.page_texts.len() as u16;
+        if page_index >= pages.len() {
+            None
+        } else {
+            let text = pages.get(page_index).and_then(|page| page.text().map(|text| text.all()));
+            Some(text.unwrap_or_else(|error| {
+                error!("PdfiumAction::IndexBook - error extracting text of page {page_index} - {error}");
+                String::new()
+            }))
+        }
+    };
+    match page_text {
+        Some(page_text) => {
+            index_job.page_texts.push(page_text);
+            Some(index_job)
+        }
+        None => {
+            send_result(global_action_sender, GlobalResult::BookTextExtracted { uuid: index_job.uuid, page_texts: index_job.page_texts });
+            None
+        }
+    }
+}
+
+// Malformed documents can have cyclic bookmark trees, so the walk is capped
+const MAX_OUTLINE_DEPTH: usize = 16;
+const MAX_OUTLINE_ENTRIES_PER_LEVEL: usize = 1000;
+
+fn get_outline(pdf: &PdfDocument) -> Vec<OutlineEntry> {
+    get_outline_level(pdf.bookmarks().root(), 0)
+}
+
+fn get_outline_level(first_bookmark: Option<PdfBookmark>, depth: usize) -> Vec<OutlineEntry> {
+    if depth >= MAX_OUTLINE_DEPTH {
+        return vec![];
+    }
+    let mut entries = vec![];
+    let mut next_bookmark = first_bookmark;
+    while let Some(bookmark) = next_bookmark {
+        if entries.len() >= MAX_OUTLINE_ENTRIES_PER_LEVEL {
+            break;
+        }
+        entries.push(OutlineEntry {
+            title: bookmark.title().unwrap_or_default(),
+            page_index: get_bookmark_page_index(&bookmark),
+            children: get_outline_level(bookmark.first_child(), depth + 1),
+        });
+        next_bookmark = bookmark.next_sibling();
+    }
+    entries
+}
+
+fn get_bookmark_page_index(bookmark: &PdfBookmark) -> Option<i32> {
+    let page_index = match bookmark.destination() {
+        Some(destination) => destination.page_index().ok(),
+        // Bookmarks can also point to their page through a GoTo action
+        None => bookmark
+            .action()?
+            .as_local_destination_action()?
+            .destination()
+            .ok()?
+            .page_index()
+            .ok(),
+    };
+    page_index.map(i32::from)
+}
+
+// None when the document can't be read, its renders just aren't stored then
+fn get_document_key(uuid: &str, path: &Path) -> Option<String> {
+    document_key(uuid, path)
+        .map_err(|error| error!("Reading metadata of pdf {uuid} from {} failed, its renders won't be stored: {error}", path.display()))
+        .ok()
+}
+
+// Every book holds on to its thumbnail, so it's kept compressed
+fn get_thumbnail(pdf: &PdfDocument, pixel_format: PixelFormat, render_disk_cache: &mut RenderDiskCache) -> Result<Arc<Bitmap>> {
+    let first_page = pdf.pages().get(0)?;
+    let size = THUMBNAIL_LAYOUT.fit(first_page.width().value, first_page.height().value);
+    let key = RenderKey { page_index: 0, size, rotation: THUMBNAIL_LAYOUT.rotation };
+    if let Some(thumbnail) = render_disk_cache.get(&key) {
+        return Ok(thumbnail);
+    }
+    let thumbnail = get_page_image(first_page, &THUMBNAIL_LAYOUT, pixel_format)?.encode(ImageEncoding::Png)?;
+    render_disk_cache.insert(&key, thumbnail.clone());
+    Ok(thumbnail)
+}
+
+pub fn get_page_image(page: PdfPage, page_layout: &PageLayout, pixel_format: PixelFormat) -> Result<Arc<Bitmap>> {
+    let (size, rgba_bytes) = render_page_bytes(page, page_layout)?;
+    Ok(Bitmap::from_rgba(size.width, size.height, rgba_bytes, pixel_format))
+}
+
+// The pdfium part of rendering a page - the raw RGBA bytes are converted separately
+pub fn render_page_bytes(page: PdfPage, page_layout: &PageLayout) -> Result<(RenderSize, Vec<u8>)> {
+    let size = page_layout.fit(page.width().value, page.height().value);
+    // Fixed size makes pdfium fit the (rotated) page into exactly the bitmap we computed
+    let config = PdfRenderConfig::new()
+        .set_fixed_size(size.width, size.height)
+        .rotate(page_layout.pdfium_rotation(), false);
+    let pdf_bitmap = page.render_with_config(&config)?;
+    Ok((size, pdf_bitmap.as_raw_bytes()))
+}
 
 pub enum PdfiumAction {
-    LoadPdf { uuid: String, file_name: String, bytes: Vec<u8> },
+    LoadPdf { uuid: String, file_name: String, path: PathBuf, password: Option<String> },
+    OpenPdf { uuid: String, path: PathBuf, password: Option<String> },
+    ClosePdf,
+    Search { search_id: String, query: String },
+    CancelSearch,
+    IndexBook { uuid: String, path: PathBuf, password: Option<String> },
+    CreateHighlight {
+        uuid: String,
+        highlight_id: String,
+        page_index: i32,
+        selection: PageSelection,
+        color: u32,
+        note: String,
+    },
+    ExportAnnotatedPdf {
+        uuid: String,
+        file_name: String,
+        path: PathBuf,
+        password: Option<String>,
+        highlights: Vec<Highlight>,
+        bookmarks: Vec<Bookmark>,
+    },
+    SetPageLayout { page_layout: PageLayout, page_index: i32 },
+    LoadTiles { page_index: i32, zoom_level: i32, region: PageRect },
     PageLoadRequested { page_index: i32 },
+    ClearCache,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{fixture_path, with_pdfium, FIXTURE_PAGES};
+
+    #[test]
+    #[ignore = "needs pdfium, run with --ignored"]
+    fn pages_render_at_the_fitted_size() {
+        with_pdfium(|pdfium| {
+            for fixture in &FIXTURE_PAGES {
+                let pdf = pdfium.load_pdf_from_file(&fixture_path(fixture.name), None).unwrap();
+                let page = pdf.pages().get(0).unwrap();
+                assert_eq!((page.width().value, page.height().value), (fixture.width, fixture.height), "{}", fixture.name);
+                for fit_mode in [FitMode::Width, FitMode::Height, FitMode::WholePage] {
+                    for rotation in [PageRotation::None, PageRotation::Degrees90, PageRotation::Degrees180, PageRotation::Degrees270] {
+                        let page_layout = PageLayout { box_width: 400, box_height: 640, fit_mode, rotation };
+                        let (size, rgba_bytes) = render_page_bytes(pdf.pages().get(0).unwrap(), &page_layout).unwrap();
+                        let context = format!("{} {fit_mode:?} {rotation:?}", fixture.name);
+                        assert_eq!(size, page_layout.fit(fixture.width, fixture.height), "{context}");
+                        assert_eq!(rgba_bytes.len(), (size.width * size.height * 4) as usize, "{context}");
+                    }
+                }
+            }
+        });
+    }
+    #[test]
+    #[ignore = "needs pdfium, run with --ignored"]
+    fn passwords_with_a_nul_are_wrong_passwords() {
+        with_pdfium(|pdfium| {
+            // The encrypted fixture opens with "password" - cut at the nul this one would open it too
+            let error = OpenDocument::load(pdfium, &fixture_path("encrypted"), Some("password\0suffix".to_string())).err().unwrap();
+            assert!(is_password_error(&error));
+            let error = OpenDocument::load(pdfium, &fixture_path("encrypted"), Some("wrong".to_string())).err().unwrap();
+            assert!(is_password_error(&error));
+            let error = OpenDocument::load(pdfium, &fixture_path("encrypted"), None).err().unwrap();
+            assert!(is_password_error(&error));
+            let pdf = OpenDocument::load(pdfium, &fixture_path("encrypted"), Some("password".to_string())).unwrap();
+            assert_eq!(pdf.pages().len(), 1);
+        });
+    }
 }
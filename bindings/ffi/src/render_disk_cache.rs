@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::SystemTime;
+use anyhow::{Context, Result};
+use crate::domain::{Bitmap, ImageEncoding, PageRotation, PixelFormat};
+use crate::page_layout::RenderSize;
+
+const RENDER_CACHE_BUDGET_BYTES: u64 = 256 * 1024 * 1024;
+const RENDER_CACHE_ENCODING: ImageEncoding = ImageEncoding::Png;
+
+// Identifies the source of a book by its uuid, size and modification time - the latter two change whenever
+// the source is replaced, so nothing has to be read to tell its renders apart. A hash of the content would take
+// reading the whole source on every open.
+pub fn document_key(uuid: &str, path: &Path) -> Result<String> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata.modified()?.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |duration| duration.as_nanos());
+    Ok(format!("{uuid}_{:x}_{modified:x}", metadata.len()))
+}
+
+// Removes the renders of every version of a book's source
+pub fn remove_document_renders(root: &Path, uuid: &str) -> Result<()> {
+    let document_directories = match fs::read_dir(root) {
+        Ok(document_directories) => document_directories,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error).context("Listing render cache directory"),
+    };
+    let prefix = format!("{uuid}_");
+    for document_directory in document_directories {
+        let document_directory = document_directory.context("Listing render cache directory")?;
+        if document_directory.file_name().to_string_lossy().starts_with(&prefix) {
+            fs::remove_dir_all(document_directory.path()).context(format!("Removing renders of {uuid}"))?;
+        }
+    }
+    Ok(())
+}
+
+// Within a document a fixed size render only depends on the page, its size and rotation - thumbnails included
+pub struct RenderKey {
+    pub page_index: i32,
+    pub size: RenderSize,
+    pub rotation: PageRotation,
+}
+
+impl RenderKey {
+    fn relative_path(&self, document_key: &str) -> PathBuf {
+        let degrees = match self.rotation {
+            PageRotation::None => 0,
+            PageRotation::Degrees90 => 90,
+            PageRotation::Degrees180 => 180,
+            PageRotation::Degrees270 => 270,
+        };
+        Path::new(document_key).join(format!("{}_{}x{}_{degrees}.png", self.page_index, self.size.width, self.size.height))
+    }
+}
+
+struct CachedFile {
+    bytes: u64,
+    last_used: u64,
+}
+
+enum DiskCacheJob {
+    Write { relative_path: PathBuf, image: Arc<Bitmap> },
+    Touch { relative_path: PathBuf },
+    Clear,
+}
+
+// Page renders kept across sessions as png files, the least recently used ones removed past the budget.
+// Renders are read right away, while encoding and writing them happens on a thread of its own so it never
+// holds up rendering.
+pub struct RenderDiskCache {
+    root: PathBuf,
+    pixel_format: PixelFormat,
+    // Key of the open document, nothing is cached without one
+    document_key: Option<String>,
+    job_sender: Sender<DiskCacheJob>,
+}
+
+impl RenderDiskCache {
+    pub fn new(root: PathBuf, pixel_format: PixelFormat) -> RenderDiskCache {
+        let (job_sender, job_receiver) = channel();
+        let writer = RenderDiskCacheWriter { root: root.clone(), files: None, usage_counter: 0 };
+        thread::spawn(move || writer.run(job_receiver));
+        RenderDiskCache { root, pixel_format, document_key: None, job_sender }
+    }
+
+    pub fn set_document_key(&mut self, document_key: Option<String>) {
+        self.document_key = document_key;
+    }
+
+    pub fn get(&mut self, key: &RenderKey) -> Option<Arc<Bitmap>> {
+        let relative_path = key.relative_path(self.document_key.as_ref()?);
+        match fs::read(self.root.join(&relative_path)) {
+            Ok(bytes) => {
+                self.send(DiskCacheJob::Touch { relative_path });
+                Some(Bitmap::from_encoded(key.size.width, key.size.height, bytes, RENDER_CACHE_ENCODING, self.pixel_format))
+            }
+            Err(error) if error.kind() == ErrorKind::NotFound => None,
+            Err(error) => {
+                error!("RenderDiskCache - error reading {} - {error}", relative_path.display());
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: &RenderKey, image: Arc<Bitmap>) {
+        if let Some(document_key) = self.document_key.as_ref() {
+            self.send(DiskCacheJob::Write { relative_path: key.relative_path(document_key), image });
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.send(DiskCacheJob::Clear);
+    }
+
+    fn send(&self, job: DiskCacheJob) {
+        if self.job_sender.send(job).is_err() {
+            error!("RenderDiskCache - the writer thread stopped");
+        }
+    }
+}
+
+// Owns the files of the cache and their recency. Recency survives restarts only as the time a render was written.
+struct RenderDiskCacheWriter {
+    root: PathBuf,
+    // Listed from the directory on first use
+    files: Option<HashMap<PathBuf, CachedFile>>,
+    usage_counter: u64,
+}
+
+impl RenderDiskCacheWriter {
+    fn run(mut self, job_receiver: Receiver<DiskCacheJob>) {
+        for job in job_receiver {
+            match job {
+                DiskCacheJob::Write { relative_path, image } => {
+                    if let Err(error) = self.write(relative_path, &image) {
+                        error!("RenderDiskCache - error storing render - {error}")
+                    }
+                }
+                DiskCacheJob::Touch { relative_path } => {
+                    self.usage_counter += 1;
+                    let usage_counter = self.usage_counter;
+                    if let Some(file) = self.files().get_mut(&relative_path) {
+                        file.last_used = usage_counter;
+                    }
+                }
+                DiskCacheJob::Clear => {
+                    if let Err(error) = self.clear() {
+                        error!("RenderDiskCache - error removing stored renders - {error}")
+                    }
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, relative_path: PathBuf, image: &Bitmap) -> Result<()> {
+        let path = self.root.join(&relative_path);
+        let bytes = image.try_encoded_bytes(RENDER_CACHE_ENCODING)?;
+        fs::create_dir_all(path.parent().context("Render without a directory")?).context("Creating render cache directory")?;
+        // Written next to its final path first so a crash never leaves a truncated render behind
+        let temporary_path = path.with_extension("tmp");
+        fs::write(&temporary_path, &bytes).context(format!("Writing {}", relative_path.display()))?;
+        fs::rename(&temporary_path, &path).context(format!("Replacing {}", relative_path.display()))?;
+        self.usage_counter += 1;
+        let cached_file = CachedFile { bytes: bytes.len() as u64, last_used: self.usage_counter };
+        self.files().insert(relative_path, cached_file);
+        self.remove_least_recently_used();
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.files = Some(HashMap::new());
+        match fs::remove_dir_all(&self.root) {
+            Ok(_) => Ok(()),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(error).context("Removing render cache directory"),
+        }
+    }
+    fn remove_least_recently_used(&mut self) {
+        let files = self.files();
+        let mut used_bytes: u64 = files.values().map(|file| file.bytes).sum();
+        let mut by_last_use: Vec<(PathBuf, u64, u64)> = files
+            .iter()
+            .map(|(relative_path, file)| (relative_path.clone(), file.last_used, file.bytes))
+            .collect();
+        by_last_use.sort_by_key(|(_, last_used, _)| *last_used);
+        for (relative_path, _, bytes) in by_last_use {
+            if used_bytes <= RENDER_CACHE_BUDGET_BYTES {
+                break;
+            }
+            // Renders of a deleted book are already gone
+            match fs::remove_file(self.root.join(&relative_path)) {
+                Ok(_) => {}
+                Err(error) if error.kind() == ErrorKind::NotFound => {}
+                Err(error) => { error!("RenderDiskCache - error removing {} - {error}", relative_path.display()) }
+            }
+            self.files().remove(&relative_path);
+            used_bytes -= bytes;
+        }
+    }
+
+    fn files(&mut self) -> &mut HashMap<PathBuf, CachedFile> {
+        if self.files.is_none() {
+            let mut listed = list_files(&self.root);
+            listed.sort_by_key(|(_, _, modified)| *modified);
+            let files = listed
+                .into_iter()
+                .map(|(relative_path, bytes, _)| {
+                    self.usage_counter += 1;
+                    (relative_path, CachedFile { bytes, last_used: self.usage_counter })
+                })
+                .collect();
+            self.files = Some(files);
+        }
+        self.files.get_or_insert_with(HashMap::new)
+    }
+}
+
+// Renders one directory per document, as paths relative to root with their size and modification time
+fn list_files(root: &Path) -> Vec<(PathBuf, u64, SystemTime)> {
+    let Ok(document_directories) = fs::read_dir(root) else {
+        return vec![];
+    };
+    document_directories
+        .flatten()
+        .filter_map(|document_directory| Some((document_directory.file_name(), fs::read_dir(document_directory.path()).ok()?)))
+        .flat_map(|(document_directory_name, renders)| {
+            renders.flatten().filter_map(move |render| {
+                let metadata = render.metadata().ok()?;
+                let relative_path = Path::new(&document_directory_name).join(render.file_name());
+                let is_render = relative_path.extension().map_or(false, |extension| extension == "png");
+                is_render.then(|| (relative_path, metadata.len(), metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH)))
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,103 @@
+use std::cmp::{max, min};
+use std::collections::BTreeSet;
+
+// How many pages before and after the requested one get rendered ahead
+pub const PAGES_AROUND: i32 = 5;
+
+// Pending page renders of the open document. Repeated requests coalesce into a single render per page,
+// the most recently requested page is rendered first and pages that scrolled out of range are dropped.
+#[derive(Default)]
+pub struct RenderScheduler {
+    focus_index: i32,
+    pending: BTreeSet<i32>,
+}
+
+impl RenderScheduler {
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn focus_index(&self) -> i32 {
+        self.focus_index
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    pub fn request(&mut self, page_index: i32, page_count: i32) {
+        let range = max(0, page_index - PAGES_AROUND)..min(page_index + PAGES_AROUND + 1, page_count);
+        self.focus_index = page_index;
+        self.pending.retain(|pending_index| range.contains(pending_index));
+        self.pending.extend(range);
+    }
+
+    // The pending page closest to the focus, the one after it when two are equally close
+    pub fn next(&mut self) -> Option<i32> {
+        let focus_index = self.focus_index;
+        let next_index = *self
+            .pending
+            .iter()
+            .min_by_key(|pending_index| ((**pending_index - focus_index).abs(), **pending_index < focus_index))?;
+        self.pending.remove(&next_index);
+        Some(next_index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(render_scheduler: &mut RenderScheduler) -> Vec<i32> {
+        std::iter::from_fn(|| render_scheduler.next()).collect()
+    }
+
+    #[test]
+    fn the_requested_page_is_rendered_first_then_the_closest_ones() {
+        let mut render_scheduler = RenderScheduler::default();
+        render_scheduler.request(10, 100);
+        assert_eq!(render_scheduler.focus_index(), 10);
+        assert_eq!(drain(&mut render_scheduler), vec![10, 11, 9, 12, 8, 13, 7, 14, 6, 15, 5]);
+        assert!(render_scheduler.is_empty());
+    }
+
+    #[test]
+    fn repeated_requests_render_each_page_once() {
+        let mut render_scheduler = RenderScheduler::default();
+        render_scheduler.request(10, 100);
+        render_scheduler.request(10, 100);
+        render_scheduler.request(12, 100);
+        let mut rendered = drain(&mut render_scheduler);
+        assert_eq!(rendered[0], 12);
+        rendered.sort();
+        assert_eq!(rendered, (7..=17).collect::<Vec<i32>>());
+    }
+
+    #[test]
+    fn pages_out_of_range_are_dropped() {
+        let mut render_scheduler = RenderScheduler::default();
+        render_scheduler.request(10, 100);
+        render_scheduler.next();
+        render_scheduler.request(40, 100);
+        let mut rendered = drain(&mut render_scheduler);
+        rendered.sort();
+        assert_eq!(rendered, (35..=45).collect::<Vec<i32>>());
+    }
+
+    #[test]
+    fn the_range_ends_with_the_document() {
+        let mut render_scheduler = RenderScheduler::default();
+        render_scheduler.request(1, 3);
+        assert_eq!(drain(&mut render_scheduler), vec![1, 2, 0]);
+        render_scheduler.request(0, 0);
+        assert!(render_scheduler.is_empty());
+    }
+
+    #[test]
+    fn cleared_requests_render_nothing() {
+        let mut render_scheduler = RenderScheduler::default();
+        render_scheduler.request(10, 100);
+        render_scheduler.clear();
+        assert_eq!(render_scheduler.next(), None);
+    }
+}
@@ -0,0 +1,60 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use pdfium_render::prelude::*;
+use uuid::Uuid;
+use crate::pdfium_manager::bind_pdfium;
+
+// Pdfium takes a global lock while it's bound, so tests using it take turns instead of deadlocking
+static PDFIUM_LOCK: Mutex<()> = Mutex::new(());
+
+pub struct FixturePage {
+    pub name: &'static str,
+    pub width: f32,
+    pub height: f32,
+}
+
+// Single page pdfs in fixtures/, sized in points
+pub const FIXTURE_PAGES: [FixturePage; 4] = [
+    FixturePage { name: "portrait", width: 612.0, height: 792.0 },
+    FixturePage { name: "landscape", width: 792.0, height: 612.0 },
+    FixturePage { name: "square", width: 600.0, height: 600.0 },
+    FixturePage { name: "very_tall", width: 300.0, height: 3000.0 },
+];
+
+pub fn fixture_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures").join(format!("{name}.pdf"))
+}
+
+// Runs test against pdfium from PDFIUM_LIBRARY_PATH or the system libraries. Tests using it are ignored by default
+// and fail when there's no pdfium to bind, run them with `make test-pdfium`
+pub fn with_pdfium(test: impl FnOnce(&Pdfium)) {
+    let _guard = PDFIUM_LOCK.lock().unwrap_or_else(|error| error.into_inner());
+    let library_search_paths: Vec<PathBuf> = env::var_os("PDFIUM_LIBRARY_PATH").map(PathBuf::from).into_iter().collect();
+    let pdfium_bindings = bind_pdfium(&library_search_paths).unwrap_or_else(|reason| panic!("pdfium is unavailable - {reason}"));
+    test(&Pdfium::new(pdfium_bindings))
+}
+
+// A directory of its own under the system temporary one, removed with everything in it when dropped
+pub struct TemporaryDirectory {
+    path: PathBuf,
+}
+
+impl TemporaryDirectory {
+    pub fn new() -> TemporaryDirectory {
+        let path = env::temp_dir().join(format!("read_mate_test_{}", Uuid::new_v4()));
+        fs::create_dir_all(&path).unwrap();
+        TemporaryDirectory { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TemporaryDirectory {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}